@@ -0,0 +1,1079 @@
+//! The component model's canonical ABI, for driving core wasm modules with [`Val`]s.
+//!
+//! Engines that only understand core wasm can call a function exported with the canonical ABI
+//! by lowering its arguments with [`lower_args`], calling the core function with the resulting
+//! [`CoreVal`]s and lifting the returned core values with [`lift_results`]. Strings and lists
+//! are copied into the guest's linear memory through the [`CoreMemory`] implementation, which
+//! allocates with the module's `cabi_realloc` export. As with any canonical ABI call, the
+//! caller should invoke the function's `cabi_post_*` export, if present, once the results have
+//! been lifted.
+//!
+//! Only UTF-8 string encoding is supported.
+
+use thiserror::Error;
+
+use crate::{TypeMismatch, Val, ValType};
+
+/// Arguments that flatten to more core values than this are passed through memory.
+pub const MAX_FLAT_PARAMS: usize = 16;
+/// Results that flatten to more core values than this are returned through memory.
+pub const MAX_FLAT_RESULTS: usize = 1;
+/// The longest list of zero-sized elements that is lifted. Such lists take up no memory, so
+/// their length isn't bounded by the size of the memory.
+pub const MAX_ZERO_SIZED_LEN: u32 = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoreType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoreVal {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl CoreVal {
+    pub fn ty(&self) -> CoreType {
+        match self {
+            CoreVal::I32(_) => CoreType::I32,
+            CoreVal::I64(_) => CoreType::I64,
+            CoreVal::F32(_) => CoreType::F32,
+            CoreVal::F64(_) => CoreType::F64,
+        }
+    }
+
+    fn zero(ty: CoreType) -> Self {
+        match ty {
+            CoreType::I32 => CoreVal::I32(0),
+            CoreType::I64 => CoreVal::I64(0),
+            CoreType::F32 => CoreVal::F32(0.0),
+            CoreType::F64 => CoreVal::F64(0.0),
+        }
+    }
+
+    /// Widens this value into a joined variant slot of type `slot`.
+    fn coerce_to(self, slot: CoreType) -> Self {
+        match (self, slot) {
+            (CoreVal::F32(v), CoreType::I32) => CoreVal::I32(v.to_bits() as i32),
+            (CoreVal::I32(v), CoreType::I64) => CoreVal::I64(v as u32 as i64),
+            (CoreVal::F32(v), CoreType::I64) => CoreVal::I64(v.to_bits() as i64),
+            (CoreVal::F64(v), CoreType::I64) => CoreVal::I64(v.to_bits() as i64),
+            (v, _) => v,
+        }
+    }
+
+    /// Narrows a joined variant slot back into a value of type `ty`.
+    fn coerce_from(self, ty: CoreType) -> Self {
+        match (self, ty) {
+            (CoreVal::I32(v), CoreType::F32) => CoreVal::F32(f32::from_bits(v as u32)),
+            (CoreVal::I64(v), CoreType::I32) => CoreVal::I32(v as i32),
+            (CoreVal::I64(v), CoreType::F32) => CoreVal::F32(f32::from_bits(v as u32)),
+            (CoreVal::I64(v), CoreType::F64) => CoreVal::F64(f64::from_bits(v as u64)),
+            (v, _) => v,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AbiError {
+    #[error("memory access of {len} bytes at {offset} is out of bounds")]
+    OutOfBounds { offset: u32, len: u64 },
+    #[error("pointer {ptr} is not aligned to {align}")]
+    Misaligned { ptr: u32, align: u32 },
+    #[error("cabi_realloc failed: {0}")]
+    Realloc(String),
+    #[error("{0} bytes do not fit into a 32-bit linear memory")]
+    TooLarge(u64),
+    #[error("list of {0} zero-sized elements is too long")]
+    ListTooLong(u32),
+    #[error("invalid discriminant {0}")]
+    InvalidDiscriminant(u32),
+    #[error("invalid char {0:#x}")]
+    InvalidChar(u32),
+    #[error("string is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("expected {expected} core values, found {found}")]
+    CoreValueCount { expected: usize, found: usize },
+    #[error("expected core value of type {expected:?}, found {found:?}")]
+    CoreValueType { expected: CoreType, found: CoreType },
+    #[error("expected {expected} arguments, found {found}")]
+    ArgumentCount { expected: usize, found: usize },
+    #[error("argument {index} has the wrong type: {mismatch}")]
+    ArgumentType {
+        index: usize,
+        mismatch: TypeMismatch,
+    },
+}
+
+/// The linear memory and allocator of a core module instance.
+pub trait CoreMemory {
+    /// The current size of the memory in bytes.
+    fn size(&self) -> u64;
+
+    fn read(&self, offset: u32, buf: &mut [u8]) -> Result<(), AbiError>;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), AbiError>;
+
+    /// Calls the module's `cabi_realloc(old_ptr, old_size, align, new_size)`.
+    fn realloc(
+        &mut self,
+        old_ptr: u32,
+        old_size: u32,
+        align: u32,
+        new_size: u32,
+    ) -> Result<u32, AbiError>;
+}
+
+fn align_to(offset: u32, align: u32) -> u32 {
+    offset.div_ceil(align) * align
+}
+
+/// `ptr + offset`, failing instead of wrapping around for pointers taken from the guest.
+fn offset(ptr: u32, offset: u32) -> Result<u32, AbiError> {
+    ptr.checked_add(offset).ok_or(AbiError::OutOfBounds {
+        offset: ptr,
+        len: offset as u64,
+    })
+}
+
+fn discriminant_size(cases: usize) -> u32 {
+    if cases <= 1 << 8 {
+        1
+    } else if cases <= 1 << 16 {
+        2
+    } else {
+        4
+    }
+}
+
+/// The cases of a variant-like type: variants, enums, options and results.
+fn cases(ty: &ValType) -> Option<Vec<Option<&ValType>>> {
+    match ty {
+        ValType::Variant(cases) => Some(cases.iter().map(|(_, ty)| ty.as_ref()).collect()),
+        ValType::Enum(cases) => Some(vec![None; cases.len()]),
+        ValType::Option(ty) => Some(vec![None, Some(ty)]),
+        ValType::Result { ok, err } => Some(vec![ok.as_deref(), err.as_deref()]),
+        _ => None,
+    }
+}
+
+/// The case index and payload of a variant-like value.
+fn case_of<'a>(val: &'a Val, ty: &ValType) -> Option<(usize, Option<&'a Val>)> {
+    match (val, ty) {
+        (Val::Variant(case, payload), ValType::Variant(cases)) => cases
+            .iter()
+            .position(|(name, _)| name == case)
+            .map(|idx| (idx, payload.as_deref())),
+        (Val::Enum(case), ValType::Enum(cases)) => cases
+            .iter()
+            .position(|name| name == case)
+            .map(|idx| (idx, None)),
+        (Val::Option(None), ValType::Option(_)) => Some((0, None)),
+        (Val::Option(Some(v)), ValType::Option(_)) => Some((1, Some(v))),
+        (Val::Result(Ok(v)), ValType::Result { .. }) => Some((0, v.as_deref())),
+        (Val::Result(Err(v)), ValType::Result { .. }) => Some((1, v.as_deref())),
+        _ => None,
+    }
+}
+
+/// Builds the variant-like value of type `ty` for case `idx`.
+fn make_case(ty: &ValType, idx: usize, payload: Option<Val>) -> Val {
+    let payload = payload.map(Box::new);
+    match ty {
+        ValType::Variant(cases) => Val::Variant(cases[idx].0.clone(), payload),
+        ValType::Enum(cases) => Val::Enum(cases[idx].clone()),
+        ValType::Option(_) => Val::Option(payload),
+        ValType::Result { .. } if idx == 0 => Val::Result(Ok(payload)),
+        ValType::Result { .. } => Val::Result(Err(payload)),
+        _ => unreachable!("only variant-like types have cases"),
+    }
+}
+
+fn flag_words(count: usize) -> usize {
+    count.div_ceil(32)
+}
+
+impl ValType {
+    /// The alignment of this type in linear memory.
+    pub fn alignment(&self) -> u32 {
+        match self {
+            ValType::Bool | ValType::S8 | ValType::U8 => 1,
+            ValType::S16 | ValType::U16 => 2,
+            ValType::S32 | ValType::U32 | ValType::Float32 | ValType::Char => 4,
+            ValType::S64 | ValType::U64 | ValType::Float64 => 8,
+            ValType::String | ValType::List(_) => 4,
+            ValType::Record(fields) => fields
+                .iter()
+                .map(|(_, ty)| ty.alignment())
+                .max()
+                .unwrap_or(1),
+            ValType::Tuple(tys) => tys.iter().map(ValType::alignment).max().unwrap_or(1),
+            ValType::Flags(names) => match names.len() {
+                0..=8 => 1,
+                9..=16 => 2,
+                _ => 4,
+            },
+            ty => {
+                let cases = cases(ty).unwrap();
+                let max_case = cases
+                    .iter()
+                    .flatten()
+                    .map(|ty| ty.alignment())
+                    .max()
+                    .unwrap_or(1);
+                discriminant_size(cases.len()).max(max_case)
+            }
+        }
+    }
+
+    /// The size of this type in linear memory.
+    pub fn size(&self) -> u32 {
+        match self {
+            ValType::Bool | ValType::S8 | ValType::U8 => 1,
+            ValType::S16 | ValType::U16 => 2,
+            ValType::S32 | ValType::U32 | ValType::Float32 | ValType::Char => 4,
+            ValType::S64 | ValType::U64 | ValType::Float64 => 8,
+            ValType::String | ValType::List(_) => 8,
+            ValType::Record(_) | ValType::Tuple(_) => {
+                let mut size = 0;
+                for ty in self.fields() {
+                    size = align_to(size, ty.alignment()) + ty.size();
+                }
+                align_to(size, self.alignment())
+            }
+            ValType::Flags(names) => match names.len() {
+                0 => 0,
+                1..=8 => 1,
+                9..=16 => 2,
+                n => 4 * flag_words(n) as u32,
+            },
+            ty => {
+                let cases = cases(ty).unwrap();
+                let max_case_align = cases
+                    .iter()
+                    .flatten()
+                    .map(|ty| ty.alignment())
+                    .max()
+                    .unwrap_or(1);
+                let max_case_size = cases
+                    .iter()
+                    .flatten()
+                    .map(|ty| ty.size())
+                    .max()
+                    .unwrap_or(0);
+                let payload_offset = align_to(discriminant_size(cases.len()), max_case_align);
+                align_to(payload_offset + max_case_size, self.alignment())
+            }
+        }
+    }
+
+    /// The core value types this type flattens to when passed directly.
+    pub fn flatten(&self) -> Vec<CoreType> {
+        let mut out = vec![];
+        self.flatten_into(&mut out);
+        out
+    }
+
+    fn flatten_into(&self, out: &mut Vec<CoreType>) {
+        match self {
+            ValType::Bool
+            | ValType::S8
+            | ValType::U8
+            | ValType::S16
+            | ValType::U16
+            | ValType::S32
+            | ValType::U32
+            | ValType::Char => out.push(CoreType::I32),
+            ValType::S64 | ValType::U64 => out.push(CoreType::I64),
+            ValType::Float32 => out.push(CoreType::F32),
+            ValType::Float64 => out.push(CoreType::F64),
+            ValType::String | ValType::List(_) => out.extend([CoreType::I32, CoreType::I32]),
+            ValType::Record(_) | ValType::Tuple(_) => {
+                self.fields().for_each(|ty| ty.flatten_into(out))
+            }
+            ValType::Flags(names) => {
+                out.extend(std::iter::repeat_n(CoreType::I32, flag_words(names.len())))
+            }
+            ty => {
+                out.push(CoreType::I32);
+                out.extend(joined_payload(&cases(ty).unwrap()));
+            }
+        }
+    }
+
+    /// The field types of a record or tuple.
+    fn fields(&self) -> Box<dyn Iterator<Item = &ValType> + '_> {
+        match self {
+            ValType::Record(fields) => Box::new(fields.iter().map(|(_, ty)| ty)),
+            ValType::Tuple(tys) => Box::new(tys.iter()),
+            _ => Box::new(std::iter::empty()),
+        }
+    }
+}
+
+/// The flattened payload slots shared by all cases of a variant.
+fn joined_payload(cases: &[Option<&ValType>]) -> Vec<CoreType> {
+    let mut joined: Vec<CoreType> = vec![];
+    for case in cases.iter().flatten() {
+        for (i, ty) in case.flatten().into_iter().enumerate() {
+            match joined.get_mut(i) {
+                None => joined.push(ty),
+                Some(slot) if *slot == ty => {}
+                Some(slot) => {
+                    let both_32 = matches!(*slot, CoreType::I32 | CoreType::F32)
+                        && matches!(ty, CoreType::I32 | CoreType::F32);
+                    *slot = if both_32 {
+                        CoreType::I32
+                    } else {
+                        CoreType::I64
+                    };
+                }
+            }
+        }
+    }
+    joined
+}
+
+/// Flattens a function signature into the core parameter and result types of its lowered
+/// export, taking the indirect parameter and result rules into account.
+pub fn flatten_signature(
+    params: &[ValType],
+    results: &[ValType],
+) -> (Vec<CoreType>, Vec<CoreType>) {
+    let mut flat_params: Vec<_> = params.iter().flat_map(ValType::flatten).collect();
+    if flat_params.len() > MAX_FLAT_PARAMS {
+        flat_params = vec![CoreType::I32];
+    }
+    let mut flat_results: Vec<_> = results.iter().flat_map(ValType::flatten).collect();
+    if flat_results.len() > MAX_FLAT_RESULTS {
+        flat_results = vec![CoreType::I32];
+    }
+    (flat_params, flat_results)
+}
+
+/// Lowers `args` of types `params` into the core arguments of a canonical ABI export.
+pub fn lower_args(
+    mem: &mut impl CoreMemory,
+    params: &[ValType],
+    args: &[Val],
+) -> Result<Vec<CoreVal>, AbiError> {
+    if params.len() != args.len() {
+        return Err(AbiError::ArgumentCount {
+            expected: params.len(),
+            found: args.len(),
+        });
+    }
+    for (index, (arg, ty)) in args.iter().zip(params).enumerate() {
+        arg.type_check(ty)
+            .map_err(|mismatch| AbiError::ArgumentType { index, mismatch })?;
+    }
+
+    let mut cx = Cx { mem };
+    let flat_count: usize = params.iter().map(|ty| ty.flatten().len()).sum();
+    if flat_count <= MAX_FLAT_PARAMS {
+        let mut out = Vec::with_capacity(flat_count);
+        for (arg, ty) in args.iter().zip(params) {
+            cx.lower_flat(arg, ty, &mut out)?;
+        }
+        Ok(out)
+    } else {
+        let tuple = ValType::Tuple(params.to_vec());
+        let ptr = cx.alloc(tuple.size(), tuple.alignment())?;
+        let mut field = 0;
+        for (arg, ty) in args.iter().zip(params) {
+            field = align_to(field, ty.alignment());
+            cx.store(arg, ty, offset(ptr, field)?)?;
+            field += ty.size();
+        }
+        Ok(vec![CoreVal::I32(ptr as i32)])
+    }
+}
+
+/// Lifts the core results of a canonical ABI export into values of types `results`.
+pub fn lift_results(
+    mem: &mut impl CoreMemory,
+    results: &[ValType],
+    flat: &[CoreVal],
+) -> Result<Vec<Val>, AbiError> {
+    let mut cx = Cx { mem };
+    let flat_count: usize = results.iter().map(|ty| ty.flatten().len()).sum();
+    if flat_count <= MAX_FLAT_RESULTS {
+        if flat.len() != flat_count {
+            return Err(AbiError::CoreValueCount {
+                expected: flat_count,
+                found: flat.len(),
+            });
+        }
+        let mut flat = flat.iter().copied();
+        results
+            .iter()
+            .map(|ty| cx.lift_flat(ty, &mut flat))
+            .collect()
+    } else {
+        let ptr = match flat {
+            [ptr] => expect_i32(Some(*ptr))? as u32,
+            _ => {
+                return Err(AbiError::CoreValueCount {
+                    expected: 1,
+                    found: flat.len(),
+                })
+            }
+        };
+        let tuple = ValType::Tuple(results.to_vec());
+        check_aligned(ptr, tuple.alignment())?;
+        let mut field = 0;
+        results
+            .iter()
+            .map(|ty| {
+                field = align_to(field, ty.alignment());
+                let val = cx.load(ty, offset(ptr, field)?)?;
+                field += ty.size();
+                Ok(val)
+            })
+            .collect()
+    }
+}
+
+fn expect_i32(val: Option<CoreVal>) -> Result<i32, AbiError> {
+    match val {
+        Some(CoreVal::I32(v)) => Ok(v),
+        Some(other) => Err(AbiError::CoreValueType {
+            expected: CoreType::I32,
+            found: other.ty(),
+        }),
+        None => Err(AbiError::CoreValueCount {
+            expected: 1,
+            found: 0,
+        }),
+    }
+}
+
+fn expect_core(val: Option<CoreVal>, ty: CoreType) -> Result<CoreVal, AbiError> {
+    match val {
+        Some(v) if v.ty() == ty => Ok(v),
+        Some(other) => Err(AbiError::CoreValueType {
+            expected: ty,
+            found: other.ty(),
+        }),
+        None => Err(AbiError::CoreValueCount {
+            expected: 1,
+            found: 0,
+        }),
+    }
+}
+
+fn check_aligned(ptr: u32, align: u32) -> Result<(), AbiError> {
+    if ptr.is_multiple_of(align) {
+        Ok(())
+    } else {
+        Err(AbiError::Misaligned { ptr, align })
+    }
+}
+
+fn lift_char(c: u32) -> Result<Val, AbiError> {
+    char::from_u32(c)
+        .map(Val::Char)
+        .ok_or(AbiError::InvalidChar(c))
+}
+
+fn flag_bits(names: &[String], flags: &[String]) -> Vec<u32> {
+    let mut words = vec![0u32; flag_words(flags.len())];
+    for (i, flag) in flags.iter().enumerate() {
+        if names.contains(flag) {
+            words[i / 32] |= 1 << (i % 32);
+        }
+    }
+    words
+}
+
+fn flags_from_bits(words: &[u32], flags: &[String]) -> Val {
+    Val::Flags(
+        flags
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| words[i / 32] & (1 << (i % 32)) != 0)
+            .map(|(_, flag)| flag.clone())
+            .collect(),
+    )
+}
+
+/// Lowering and lifting state; values are assumed to have been type-checked.
+struct Cx<'a, M> {
+    mem: &'a mut M,
+}
+
+impl<M: CoreMemory> Cx<'_, M> {
+    fn alloc(&mut self, size: u32, align: u32) -> Result<u32, AbiError> {
+        let ptr = self.mem.realloc(0, 0, align, size)?;
+        check_aligned(ptr, align)?;
+        Ok(ptr)
+    }
+
+    fn read<const N: usize>(&self, offset: u32) -> Result<[u8; N], AbiError> {
+        let mut buf = [0; N];
+        self.mem.read(offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Checks that the `len` bytes at `ptr` are within the memory, before anything is
+    /// allocated for them.
+    fn check_range(&self, ptr: u32, len: u64) -> Result<(), AbiError> {
+        if ptr as u64 + len <= self.mem.size() {
+            Ok(())
+        } else {
+            Err(AbiError::OutOfBounds { offset: ptr, len })
+        }
+    }
+
+    /// Copies a list or string into freshly allocated guest memory, returning `(ptr, len)`.
+    fn lower_list(&mut self, val: &Val, ty: &ValType) -> Result<(u32, u32), AbiError> {
+        match (val, ty) {
+            (Val::String(s), ValType::String) => {
+                let len = u32::try_from(s.len()).map_err(|_| AbiError::TooLarge(s.len() as u64))?;
+                let ptr = self.alloc(len, 1)?;
+                self.mem.write(ptr, s.as_bytes())?;
+                Ok((ptr, len))
+            }
+            (Val::List(items), ValType::List(elem)) => {
+                let bytes = (items.len() as u64).saturating_mul(elem.size() as u64);
+                let size = u32::try_from(bytes).map_err(|_| AbiError::TooLarge(bytes))?;
+                let len = u32::try_from(items.len()).map_err(|_| AbiError::TooLarge(bytes))?;
+                let ptr = self.alloc(size, elem.alignment())?;
+                for (i, item) in items.iter().enumerate() {
+                    // `i * size` is below `size`, which fits into a `u32`.
+                    self.store(item, elem, offset(ptr, i as u32 * elem.size())?)?;
+                }
+                Ok((ptr, len))
+            }
+            _ => unreachable!("values are type-checked before lowering"),
+        }
+    }
+
+    fn lift_list(&mut self, ty: &ValType, ptr: u32, len: u32) -> Result<Val, AbiError> {
+        match ty {
+            ValType::String => {
+                self.check_range(ptr, len as u64)?;
+                let mut buf = vec![0; len as usize];
+                self.mem.read(ptr, &mut buf)?;
+                String::from_utf8(buf)
+                    .map(Val::String)
+                    .map_err(|_| AbiError::InvalidUtf8)
+            }
+            ValType::List(elem) => {
+                check_aligned(ptr, elem.alignment())?;
+                if elem.size() == 0 && len > MAX_ZERO_SIZED_LEN {
+                    return Err(AbiError::ListTooLong(len));
+                }
+                self.check_range(ptr, len as u64 * elem.size() as u64)?;
+                // Within the memory, so every element offset fits into a `u32`.
+                (0..len)
+                    .map(|i| self.load(elem, ptr + i * elem.size()))
+                    .collect::<Result<_, _>>()
+                    .map(Val::List)
+            }
+            _ => unreachable!("only strings and lists are lifted from (ptr, len)"),
+        }
+    }
+
+    fn lower_flat(
+        &mut self,
+        val: &Val,
+        ty: &ValType,
+        out: &mut Vec<CoreVal>,
+    ) -> Result<(), AbiError> {
+        match (val, ty) {
+            (Val::Bool(v), _) => out.push(CoreVal::I32(*v as i32)),
+            (Val::S8(v), _) => out.push(CoreVal::I32(*v as i32)),
+            (Val::U8(v), _) => out.push(CoreVal::I32(*v as i32)),
+            (Val::S16(v), _) => out.push(CoreVal::I32(*v as i32)),
+            (Val::U16(v), _) => out.push(CoreVal::I32(*v as i32)),
+            (Val::S32(v), _) => out.push(CoreVal::I32(*v)),
+            (Val::U32(v), _) => out.push(CoreVal::I32(*v as i32)),
+            (Val::S64(v), _) => out.push(CoreVal::I64(*v)),
+            (Val::U64(v), _) => out.push(CoreVal::I64(*v as i64)),
+            (Val::Float32(v), _) => out.push(CoreVal::F32(*v)),
+            (Val::Float64(v), _) => out.push(CoreVal::F64(*v)),
+            (Val::Char(v), _) => out.push(CoreVal::I32(*v as i32)),
+            (Val::String(_) | Val::List(_), _) => {
+                let (ptr, len) = self.lower_list(val, ty)?;
+                out.extend([CoreVal::I32(ptr as i32), CoreVal::I32(len as i32)]);
+            }
+            (Val::Record(fields), _) => {
+                for ((_, val), ty) in fields.iter().zip(ty.fields()) {
+                    self.lower_flat(val, ty, out)?;
+                }
+            }
+            (Val::Tuple(items), _) => {
+                for (val, ty) in items.iter().zip(ty.fields()) {
+                    self.lower_flat(val, ty, out)?;
+                }
+            }
+            (Val::Flags(names), ValType::Flags(flags)) => out.extend(
+                flag_bits(names, flags)
+                    .into_iter()
+                    .map(|w| CoreVal::I32(w as i32)),
+            ),
+            (val, ty) => {
+                let cases = cases(ty).unwrap();
+                let joined = joined_payload(&cases);
+                let (idx, payload) = case_of(val, ty).unwrap();
+                out.push(CoreVal::I32(idx as i32));
+                let mut flat = vec![];
+                if let (Some(val), Some(ty)) = (payload, cases[idx]) {
+                    self.lower_flat(val, ty, &mut flat)?;
+                }
+                for (i, slot) in joined.into_iter().enumerate() {
+                    out.push(match flat.get(i) {
+                        Some(v) => v.coerce_to(slot),
+                        None => CoreVal::zero(slot),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lift_flat(
+        &mut self,
+        ty: &ValType,
+        flat: &mut dyn Iterator<Item = CoreVal>,
+    ) -> Result<Val, AbiError> {
+        let i32 = |flat: &mut dyn Iterator<Item = CoreVal>| expect_i32(flat.next());
+        Ok(match ty {
+            ValType::Bool => Val::Bool(i32(flat)? != 0),
+            ValType::S8 => Val::S8(i32(flat)? as i8),
+            ValType::U8 => Val::U8(i32(flat)? as u8),
+            ValType::S16 => Val::S16(i32(flat)? as i16),
+            ValType::U16 => Val::U16(i32(flat)? as u16),
+            ValType::S32 => Val::S32(i32(flat)?),
+            ValType::U32 => Val::U32(i32(flat)? as u32),
+            ValType::Char => lift_char(i32(flat)? as u32)?,
+            ValType::S64 | ValType::U64 => match expect_core(flat.next(), CoreType::I64)? {
+                CoreVal::I64(v) if *ty == ValType::S64 => Val::S64(v),
+                CoreVal::I64(v) => Val::U64(v as u64),
+                _ => unreachable!(),
+            },
+            ValType::Float32 => match expect_core(flat.next(), CoreType::F32)? {
+                CoreVal::F32(v) => Val::Float32(v),
+                _ => unreachable!(),
+            },
+            ValType::Float64 => match expect_core(flat.next(), CoreType::F64)? {
+                CoreVal::F64(v) => Val::Float64(v),
+                _ => unreachable!(),
+            },
+            ValType::String | ValType::List(_) => {
+                let ptr = i32(flat)? as u32;
+                let len = i32(flat)? as u32;
+                self.lift_list(ty, ptr, len)?
+            }
+            ValType::Record(fields) => Val::Record(
+                fields
+                    .iter()
+                    .map(|(name, ty)| Ok((name.clone(), self.lift_flat(ty, flat)?)))
+                    .collect::<Result<_, AbiError>>()?,
+            ),
+            ValType::Tuple(tys) => Val::Tuple(
+                tys.iter()
+                    .map(|ty| self.lift_flat(ty, flat))
+                    .collect::<Result<_, _>>()?,
+            ),
+            ValType::Flags(flags) => {
+                let words = (0..flag_words(flags.len()))
+                    .map(|_| i32(flat).map(|w| w as u32))
+                    .collect::<Result<Vec<_>, _>>()?;
+                flags_from_bits(&words, flags)
+            }
+            ty => {
+                let cases = cases(ty).unwrap();
+                let joined = joined_payload(&cases);
+                let disc = i32(flat)? as u32;
+                let slots = joined
+                    .iter()
+                    .map(|slot| expect_core(flat.next(), *slot))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let case = *cases
+                    .get(disc as usize)
+                    .ok_or(AbiError::InvalidDiscriminant(disc))?;
+                let payload = match case {
+                    Some(case_ty) => {
+                        let mut payload = case_ty
+                            .flatten()
+                            .into_iter()
+                            .zip(slots)
+                            .map(|(ty, slot)| slot.coerce_from(ty));
+                        Some(self.lift_flat(case_ty, &mut payload)?)
+                    }
+                    None => None,
+                };
+                make_case(ty, disc as usize, payload)
+            }
+        })
+    }
+
+    fn store(&mut self, val: &Val, ty: &ValType, ptr: u32) -> Result<(), AbiError> {
+        match (val, ty) {
+            (Val::Bool(v), _) => self.mem.write(ptr, &[*v as u8]),
+            (Val::S8(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::U8(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::S16(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::U16(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::S32(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::U32(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::S64(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::U64(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::Float32(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::Float64(v), _) => self.mem.write(ptr, &v.to_le_bytes()),
+            (Val::Char(v), _) => self.mem.write(ptr, &(*v as u32).to_le_bytes()),
+            (Val::String(_) | Val::List(_), _) => {
+                let (data, len) = self.lower_list(val, ty)?;
+                self.mem.write(ptr, &data.to_le_bytes())?;
+                self.mem.write(offset(ptr, 4)?, &len.to_le_bytes())
+            }
+            (Val::Record(_) | Val::Tuple(_), _) => {
+                let vals: Vec<&Val> = match val {
+                    Val::Record(fields) => fields.iter().map(|(_, v)| v).collect(),
+                    Val::Tuple(items) => items.iter().collect(),
+                    _ => unreachable!(),
+                };
+                let mut field = 0;
+                for (val, ty) in vals.into_iter().zip(ty.fields()) {
+                    field = align_to(field, ty.alignment());
+                    self.store(val, ty, offset(ptr, field)?)?;
+                    field += ty.size();
+                }
+                Ok(())
+            }
+            (Val::Flags(names), ValType::Flags(flags)) => {
+                let words = flag_bits(names, flags);
+                match flags.len() {
+                    0 => Ok(()),
+                    1..=8 => self.mem.write(ptr, &[words[0] as u8]),
+                    9..=16 => self.mem.write(ptr, &(words[0] as u16).to_le_bytes()),
+                    _ => words.iter().enumerate().try_for_each(|(i, w)| {
+                        self.mem.write(offset(ptr, 4 * i as u32)?, &w.to_le_bytes())
+                    }),
+                }
+            }
+            (val, ty) => {
+                let cases = cases(ty).unwrap();
+                let (idx, payload) = case_of(val, ty).unwrap();
+                let disc_size = discriminant_size(cases.len());
+                self.mem
+                    .write(ptr, &(idx as u32).to_le_bytes()[..disc_size as usize])?;
+                if let (Some(val), Some(case_ty)) = (payload, cases[idx]) {
+                    let max_case_align = cases
+                        .iter()
+                        .flatten()
+                        .map(|ty| ty.alignment())
+                        .max()
+                        .unwrap_or(1);
+                    let payload = offset(ptr, align_to(disc_size, max_case_align))?;
+                    self.store(val, case_ty, payload)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn load(&mut self, ty: &ValType, ptr: u32) -> Result<Val, AbiError> {
+        Ok(match ty {
+            ValType::Bool => Val::Bool(self.read::<1>(ptr)?[0] != 0),
+            ValType::S8 => Val::S8(i8::from_le_bytes(self.read(ptr)?)),
+            ValType::U8 => Val::U8(u8::from_le_bytes(self.read(ptr)?)),
+            ValType::S16 => Val::S16(i16::from_le_bytes(self.read(ptr)?)),
+            ValType::U16 => Val::U16(u16::from_le_bytes(self.read(ptr)?)),
+            ValType::S32 => Val::S32(i32::from_le_bytes(self.read(ptr)?)),
+            ValType::U32 => Val::U32(u32::from_le_bytes(self.read(ptr)?)),
+            ValType::S64 => Val::S64(i64::from_le_bytes(self.read(ptr)?)),
+            ValType::U64 => Val::U64(u64::from_le_bytes(self.read(ptr)?)),
+            ValType::Float32 => Val::Float32(f32::from_le_bytes(self.read(ptr)?)),
+            ValType::Float64 => Val::Float64(f64::from_le_bytes(self.read(ptr)?)),
+            ValType::Char => lift_char(u32::from_le_bytes(self.read(ptr)?))?,
+            ValType::String | ValType::List(_) => {
+                let data = u32::from_le_bytes(self.read(ptr)?);
+                let len = u32::from_le_bytes(self.read(offset(ptr, 4)?)?);
+                self.lift_list(ty, data, len)?
+            }
+            ValType::Record(_) | ValType::Tuple(_) => {
+                let mut field = 0;
+                let vals = ty
+                    .fields()
+                    .map(|ty| {
+                        field = align_to(field, ty.alignment());
+                        let val = self.load(ty, offset(ptr, field)?)?;
+                        field += ty.size();
+                        Ok(val)
+                    })
+                    .collect::<Result<Vec<_>, AbiError>>()?;
+                match ty {
+                    ValType::Record(fields) => Val::Record(
+                        fields
+                            .iter()
+                            .map(|(name, _)| name.clone())
+                            .zip(vals)
+                            .collect(),
+                    ),
+                    _ => Val::Tuple(vals),
+                }
+            }
+            ValType::Flags(flags) => {
+                let words = match flags.len() {
+                    0 => vec![],
+                    1..=8 => vec![self.read::<1>(ptr)?[0] as u32],
+                    9..=16 => vec![u16::from_le_bytes(self.read(ptr)?) as u32],
+                    n => (0..flag_words(n))
+                        .map(|i| {
+                            self.read(offset(ptr, 4 * i as u32)?)
+                                .map(u32::from_le_bytes)
+                        })
+                        .collect::<Result<_, _>>()?,
+                };
+                flags_from_bits(&words, flags)
+            }
+            ty => {
+                let cases = cases(ty).unwrap();
+                let disc_size = discriminant_size(cases.len());
+                let mut disc = [0; 4];
+                self.mem.read(ptr, &mut disc[..disc_size as usize])?;
+                let disc = u32::from_le_bytes(disc);
+                let case = *cases
+                    .get(disc as usize)
+                    .ok_or(AbiError::InvalidDiscriminant(disc))?;
+                let payload = match case {
+                    Some(case_ty) => {
+                        let max_case_align = cases
+                            .iter()
+                            .flatten()
+                            .map(|ty| ty.alignment())
+                            .max()
+                            .unwrap_or(1);
+                        let payload = offset(ptr, align_to(disc_size, max_case_align))?;
+                        Some(self.load(case_ty, payload)?)
+                    }
+                    None => None,
+                };
+                make_case(ty, disc as usize, payload)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A linear memory with a bump allocator.
+    struct TestMemory {
+        bytes: Vec<u8>,
+        next: u32,
+    }
+
+    impl TestMemory {
+        fn new(size: usize) -> Self {
+            Self {
+                bytes: vec![0; size],
+                next: 8,
+            }
+        }
+
+        fn range(&self, offset: u32, len: usize) -> Result<std::ops::Range<usize>, AbiError> {
+            let start = offset as usize;
+            match start.checked_add(len) {
+                Some(end) if end <= self.bytes.len() => Ok(start..end),
+                _ => Err(AbiError::OutOfBounds {
+                    offset,
+                    len: len as u64,
+                }),
+            }
+        }
+    }
+
+    impl CoreMemory for TestMemory {
+        fn size(&self) -> u64 {
+            self.bytes.len() as u64
+        }
+
+        fn read(&self, offset: u32, buf: &mut [u8]) -> Result<(), AbiError> {
+            buf.copy_from_slice(&self.bytes[self.range(offset, buf.len())?]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), AbiError> {
+            let range = self.range(offset, bytes.len())?;
+            self.bytes[range].copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn realloc(&mut self, _: u32, _: u32, align: u32, size: u32) -> Result<u32, AbiError> {
+            let ptr = align_to(self.next, align);
+            self.range(ptr, size as usize)
+                .map_err(|err| AbiError::Realloc(err.to_string()))?;
+            self.next = ptr + size;
+            Ok(ptr)
+        }
+    }
+
+    /// Lifts a single result that is returned through memory at `ptr`.
+    fn lift(mem: &mut TestMemory, ty: ValType, ptr: u32) -> Result<Val, AbiError> {
+        lift_results(mem, &[ty], &[CoreVal::I32(ptr as i32)]).map(|mut vals| vals.remove(0))
+    }
+
+    /// Writes a `(ptr, len)` pair to the start of the memory, for [`lift`].
+    fn write_list(mem: &mut TestMemory, ptr: u32, len: u32) {
+        mem.write(0, &ptr.to_le_bytes()).unwrap();
+        mem.write(4, &len.to_le_bytes()).unwrap();
+    }
+
+    #[test]
+    fn lowered_arguments_lift_back() {
+        let mut mem = TestMemory::new(1024);
+        let params: Vec<_> = (0..4)
+            .map(|_| {
+                ValType::Record(vec![
+                    ("name".into(), ValType::String),
+                    ("scores".into(), ValType::List(Box::new(ValType::U16))),
+                    ("rank".into(), ValType::Option(Box::new(ValType::S64))),
+                ])
+            })
+            .collect();
+        let args: Vec<_> = (0..4)
+            .map(|i| {
+                Val::Record(vec![
+                    ("name".into(), Val::String(format!("player {i}"))),
+                    ("scores".into(), Val::List(vec![Val::U16(i), Val::U16(7)])),
+                    ("rank".into(), Val::Option(Some(Box::new(Val::S64(-1))))),
+                ])
+            })
+            .collect();
+        // 4 * 5 flat values are passed through memory.
+        let flat = lower_args(&mut mem, &params, &args).unwrap();
+        let [CoreVal::I32(ptr)] = flat[..] else {
+            panic!("expected a pointer, found {flat:?}");
+        };
+        let lifted = lift_results(&mut mem, &params, &[CoreVal::I32(ptr)]).unwrap();
+        assert_eq!(format!("{lifted:?}"), format!("{args:?}"));
+    }
+
+    #[test]
+    fn out_of_bounds_lists_are_errors() {
+        let mut mem = TestMemory::new(64);
+        write_list(&mut mem, 60, 8);
+        assert!(matches!(
+            lift(&mut mem, ValType::String, 0),
+            Err(AbiError::OutOfBounds { offset: 60, len: 8 })
+        ));
+        write_list(&mut mem, 56, 2);
+        let list = ValType::List(Box::new(ValType::U64));
+        assert!(matches!(
+            lift(&mut mem, list, 0),
+            Err(AbiError::OutOfBounds {
+                offset: 56,
+                len: 16
+            })
+        ));
+    }
+
+    #[test]
+    fn huge_lengths_fail_before_allocating() {
+        let mut mem = TestMemory::new(64);
+        write_list(&mut mem, 8, u32::MAX);
+        assert!(matches!(
+            lift(&mut mem, ValType::String, 0),
+            Err(AbiError::OutOfBounds { .. })
+        ));
+        let list = ValType::List(Box::new(ValType::U32));
+        assert!(matches!(
+            lift(&mut mem, list, 0),
+            Err(AbiError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn zero_sized_lists_are_capped() {
+        let mut mem = TestMemory::new(64);
+        let list = ValType::List(Box::new(ValType::Tuple(vec![])));
+        write_list(&mut mem, 8, MAX_ZERO_SIZED_LEN);
+        match lift(&mut mem, list.clone(), 0) {
+            Ok(Val::List(items)) => assert_eq!(items.len(), MAX_ZERO_SIZED_LEN as usize),
+            other => panic!("expected a list, found {other:?}"),
+        }
+        write_list(&mut mem, 8, u32::MAX);
+        assert!(matches!(
+            lift(&mut mem, list, 0),
+            Err(AbiError::ListTooLong(u32::MAX))
+        ));
+    }
+
+    /// A full 32-bit memory of zeroes, so that reads near its end succeed.
+    struct Zeroes;
+
+    impl CoreMemory for Zeroes {
+        fn size(&self) -> u64 {
+            1 << 32
+        }
+
+        fn read(&self, _: u32, buf: &mut [u8]) -> Result<(), AbiError> {
+            buf.fill(0);
+            Ok(())
+        }
+
+        fn write(&mut self, _: u32, _: &[u8]) -> Result<(), AbiError> {
+            Ok(())
+        }
+
+        fn realloc(&mut self, _: u32, _: u32, _: u32, _: u32) -> Result<u32, AbiError> {
+            Err(AbiError::Realloc("read-only".into()))
+        }
+    }
+
+    #[test]
+    fn overflowing_pointers_are_errors() {
+        let ptr = CoreVal::I32((u32::MAX - 3) as i32);
+        let pair = ValType::Tuple(vec![ValType::U32, ValType::U32]);
+        assert!(matches!(
+            lift_results(&mut Zeroes, &[pair.clone(), pair], &[ptr]),
+            Err(AbiError::OutOfBounds { .. })
+        ));
+        let strings = [ValType::String, ValType::String];
+        assert!(matches!(
+            lift_results(&mut Zeroes, &strings, &[ptr]),
+            Err(AbiError::OutOfBounds { .. })
+        ));
+        let ptr = CoreVal::I32((u32::MAX - 1) as i32);
+        let options = [ValType::Option(Box::new(ValType::U16)), ValType::U8];
+        assert!(matches!(
+            lift_results(&mut Zeroes, &options, &[ptr]),
+            Err(AbiError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        let mut mem = TestMemory::new(64);
+        let option = ValType::Option(Box::new(ValType::U8));
+        mem.write(8, &[2]).unwrap();
+        assert!(matches!(
+            lift(&mut mem, option, 8),
+            Err(AbiError::InvalidDiscriminant(2))
+        ));
+        mem.write(8, &0xd800u32.to_le_bytes()).unwrap();
+        let chars = ValType::List(Box::new(ValType::Char));
+        write_list(&mut mem, 8, 1);
+        assert!(matches!(
+            lift(&mut mem, chars, 0),
+            Err(AbiError::InvalidChar(0xd800))
+        ));
+        mem.write(8, &[0xff]).unwrap();
+        write_list(&mut mem, 8, 1);
+        assert!(matches!(
+            lift(&mut mem, ValType::String, 0),
+            Err(AbiError::InvalidUtf8)
+        ));
+    }
+}
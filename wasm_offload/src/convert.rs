@@ -0,0 +1,517 @@
+use std::fmt;
+
+use crate::{
+    path::{PathSegment, ValPath},
+    Val,
+};
+
+/// Conversion from a [`Val`] returned by an offload target back into a Rust value.
+pub trait FromVal: Sized {
+    fn from_val(val: Val) -> Result<Self, FromValError>;
+}
+
+/// A [`Val`] did not have the shape the receiving Rust type expects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FromValError {
+    path: ValPath,
+    expected: String,
+    found: String,
+}
+
+impl FromValError {
+    pub fn new(expected: impl Into<String>, found: &Val) -> Self {
+        Self {
+            path: ValPath::new(),
+            expected: expected.into(),
+            found: found.kind().to_string(),
+        }
+    }
+
+    /// An error that is not about the kind of value, e.g. a missing field or an
+    /// out-of-range integer.
+    pub fn custom(expected: impl Into<String>, found: impl Into<String>) -> Self {
+        Self {
+            path: ValPath::new(),
+            expected: expected.into(),
+            found: found.into(),
+        }
+    }
+
+    pub fn at(mut self, segment: PathSegment) -> Self {
+        self.path.prepend(segment);
+        self
+    }
+
+    pub fn at_field(self, name: impl Into<String>) -> Self {
+        self.at(PathSegment::Field(name.into()))
+    }
+
+    pub fn at_index(self, idx: usize) -> Self {
+        self.at(PathSegment::Index(idx))
+    }
+
+    pub fn path(&self) -> &ValPath {
+        &self.path
+    }
+
+    pub fn expected(&self) -> &str {
+        &self.expected
+    }
+
+    pub fn found(&self) -> &str {
+        &self.found
+    }
+}
+
+impl fmt::Display for FromValError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)?;
+        if !self.path.is_empty() {
+            write!(f, " at `{}`", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for FromValError {}
+
+/// The fields of a [`Val::Record`], taken out one by one while building a struct.
+pub struct RecordFields(Vec<(String, Val)>);
+
+impl RecordFields {
+    pub fn new(val: Val, expected: &str) -> Result<Self, FromValError> {
+        match val {
+            Val::Record(fields) => Ok(Self(fields)),
+            other => Err(FromValError::new(expected, &other)),
+        }
+    }
+
+    pub fn take<T: FromVal>(&mut self, name: &str) -> Result<T, FromValError> {
+        let Some(idx) = self.0.iter().position(|(n, _)| n == name) else {
+            return Err(FromValError::custom(format!("field `{name}`"), "nothing"));
+        };
+        let (_, val) = self.0.swap_remove(idx);
+        T::from_val(val).map_err(|e| e.at_field(name))
+    }
+}
+
+/// Converts a Rust value into an optional case payload, representing `()` as no payload so
+/// that `Result<(), E>` maps to `result<_, E>`.
+fn into_payload(val: impl Into<Val>) -> Option<Box<Val>> {
+    match val.into() {
+        Val::Tuple(items) if items.is_empty() => None,
+        val => Some(Box::new(val)),
+    }
+}
+
+/// Converts an optional case payload, treating a missing payload as `()`.
+fn from_payload<T: FromVal>(payload: Option<Box<Val>>, case: &str) -> Result<T, FromValError> {
+    let val = payload.map(|v| *v).unwrap_or(Val::Tuple(vec![]));
+    T::from_val(val).map_err(|e| e.at(PathSegment::Case(case.to_string())))
+}
+
+macro_rules! impl_from_val {
+    ($($ty:ty => $variant:ident, $name:literal;)*) => {
+        $(
+            impl FromVal for $ty {
+                fn from_val(val: Val) -> Result<Self, FromValError> {
+                    match val {
+                        Val::$variant(v) => Ok(v),
+                        other => Err(FromValError::new($name, &other)),
+                    }
+                }
+            }
+
+            impl TryFrom<Val> for $ty {
+                type Error = FromValError;
+
+                fn try_from(val: Val) -> Result<Self, Self::Error> {
+                    <$ty as FromVal>::from_val(val)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_val! {
+    bool => Bool, "bool";
+    i8 => S8, "s8";
+    u8 => U8, "u8";
+    i16 => S16, "s16";
+    u16 => U16, "u16";
+    i32 => S32, "s32";
+    u32 => U32, "u32";
+    i64 => S64, "s64";
+    u64 => U64, "u64";
+    f32 => Float32, "f32";
+    f64 => Float64, "f64";
+    char => Char, "char";
+    String => String, "string";
+}
+
+impl FromVal for usize {
+    fn from_val(val: Val) -> Result<Self, FromValError> {
+        let v = u64::from_val(val)?;
+        usize::try_from(v).map_err(|_| FromValError::custom("usize", format!("out-of-range {v}")))
+    }
+}
+
+impl FromVal for isize {
+    fn from_val(val: Val) -> Result<Self, FromValError> {
+        let v = i64::from_val(val)?;
+        isize::try_from(v).map_err(|_| FromValError::custom("isize", format!("out-of-range {v}")))
+    }
+}
+
+impl TryFrom<Val> for usize {
+    type Error = FromValError;
+
+    fn try_from(val: Val) -> Result<Self, Self::Error> {
+        <usize as FromVal>::from_val(val)
+    }
+}
+
+impl TryFrom<Val> for isize {
+    type Error = FromValError;
+
+    fn try_from(val: Val) -> Result<Self, Self::Error> {
+        <isize as FromVal>::from_val(val)
+    }
+}
+
+impl<T: Into<Val>> From<Vec<T>> for Val {
+    fn from(items: Vec<T>) -> Self {
+        Val::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: FromVal> TryFrom<Val> for Vec<T> {
+    type Error = FromValError;
+
+    fn try_from(val: Val) -> Result<Self, Self::Error> {
+        <Vec<T> as FromVal>::from_val(val)
+    }
+}
+
+impl<T: FromVal> FromVal for Vec<T> {
+    fn from_val(val: Val) -> Result<Self, FromValError> {
+        match val {
+            Val::List(items) => items
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| T::from_val(v).map_err(|e| e.at_index(i)))
+                .collect(),
+            other => Err(FromValError::new("list", &other)),
+        }
+    }
+}
+
+impl<T: Into<Val>> From<Option<T>> for Val {
+    fn from(value: Option<T>) -> Self {
+        Val::Option(value.map(|v| Box::new(v.into())))
+    }
+}
+
+impl<T: FromVal> TryFrom<Val> for Option<T> {
+    type Error = FromValError;
+
+    fn try_from(val: Val) -> Result<Self, Self::Error> {
+        <Option<T> as FromVal>::from_val(val)
+    }
+}
+
+impl<T: FromVal> FromVal for Option<T> {
+    fn from_val(val: Val) -> Result<Self, FromValError> {
+        match val {
+            Val::Option(None) => Ok(None),
+            Val::Option(Some(v)) => from_payload(Some(v), "some").map(Some),
+            other => Err(FromValError::new("option", &other)),
+        }
+    }
+}
+
+impl<T: Into<Val>, E: Into<Val>> From<Result<T, E>> for Val {
+    fn from(value: Result<T, E>) -> Self {
+        Val::Result(value.map(into_payload).map_err(into_payload))
+    }
+}
+
+impl<T: FromVal, E: FromVal> TryFrom<Val> for Result<T, E> {
+    type Error = FromValError;
+
+    fn try_from(val: Val) -> Result<Self, Self::Error> {
+        <Result<T, E> as FromVal>::from_val(val)
+    }
+}
+
+impl<T: FromVal, E: FromVal> FromVal for Result<T, E> {
+    fn from_val(val: Val) -> Result<Self, FromValError> {
+        match val {
+            Val::Result(Ok(v)) => from_payload(v, "ok").map(Ok),
+            Val::Result(Err(e)) => from_payload(e, "err").map(Err),
+            other => Err(FromValError::new("result", &other)),
+        }
+    }
+}
+
+macro_rules! impl_tuple_from_val {
+    ($len:literal; $($ty:ident),*) => {
+        impl<$($ty: FromVal),*> FromVal for ($($ty,)*) {
+            #[allow(unused_variables, unused_mut)]
+            fn from_val(val: Val) -> Result<Self, FromValError> {
+                match val {
+                    Val::Tuple(items) if items.len() == $len => {
+                        let mut items = items.into_iter().enumerate();
+                        Ok(($({
+                            let (i, v) = items.next().unwrap();
+                            $ty::from_val(v).map_err(|e| e.at_index(i))?
+                        },)*))
+                    }
+                    other => Err(FromValError::new(concat!("tuple of ", $len), &other)),
+                }
+            }
+        }
+
+        impl<$($ty: FromVal),*> TryFrom<Val> for ($($ty,)*) {
+            type Error = FromValError;
+
+            fn try_from(val: Val) -> Result<Self, Self::Error> {
+                <($($ty,)*) as FromVal>::from_val(val)
+            }
+        }
+
+        impl<$($ty: Into<Val>),*> From<($($ty,)*)> for Val {
+            #[allow(non_snake_case)]
+            fn from(($($ty,)*): ($($ty,)*)) -> Self {
+                Val::Tuple(vec![$($ty.into()),*])
+            }
+        }
+    };
+}
+
+impl_tuple_from_val!(0;);
+impl_tuple_from_val!(1; A);
+impl_tuple_from_val!(2; A, B);
+impl_tuple_from_val!(3; A, B, C);
+impl_tuple_from_val!(4; A, B, C, D);
+impl_tuple_from_val!(5; A, B, C, D, E);
+impl_tuple_from_val!(6; A, B, C, D, E, F);
+impl_tuple_from_val!(7; A, B, C, D, E, F, G);
+impl_tuple_from_val!(8; A, B, C, D, E, F, G, H);
+impl_tuple_from_val!(9; A, B, C, D, E, F, G, H, I);
+impl_tuple_from_val!(10; A, B, C, D, E, F, G, H, I, J);
+impl_tuple_from_val!(11; A, B, C, D, E, F, G, H, I, J, K);
+impl_tuple_from_val!(12; A, B, C, D, E, F, G, H, I, J, K, L);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    impl FromVal for Point {
+        fn from_val(val: Val) -> Result<Self, FromValError> {
+            let mut fields = RecordFields::new(val, "record `point`")?;
+            Ok(Self {
+                x: fields.take("x")?,
+                y: fields.take("y")?,
+            })
+        }
+    }
+
+    fn point(x: Val, y: Val) -> Val {
+        Val::Record(vec![("x".to_string(), x), ("y".to_string(), y)])
+    }
+
+    #[test]
+    fn records_take_fields_by_name() {
+        let val = Val::Record(vec![
+            ("y".to_string(), Val::S32(2)),
+            ("x".to_string(), Val::S32(1)),
+        ]);
+        let p = Point::from_val(val).unwrap();
+        assert_eq!((p.x, p.y), (1, 2));
+    }
+
+    #[test]
+    fn missing_fields_and_wrong_kinds_are_reported() {
+        let err = Point::from_val(Val::Record(vec![("x".to_string(), Val::S32(1))]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "expected field `y`, found nothing");
+
+        let err = Point::from_val(Val::S32(1)).err().unwrap();
+        assert_eq!(err.to_string(), "expected record `point`, found s32");
+        assert!(err.path().is_empty());
+    }
+
+    #[test]
+    fn errors_name_the_path_to_the_failing_value() {
+        let val = Val::Tuple(vec![
+            point(Val::S32(1), Val::S32(2)),
+            point(Val::String("one".to_string()), Val::S32(2)),
+        ]);
+        let err = <(Point, Point)>::from_val(val).err().unwrap();
+        assert_eq!(err.path().to_string(), "[1].x");
+        assert_eq!(err.to_string(), "expected s32, found string at `[1].x`");
+
+        let val = Val::Record(vec![(
+            "points".to_string(),
+            Val::List(vec![
+                Val::Option(None),
+                Val::Option(None),
+                Val::Option(None),
+                Val::Option(Some(Box::new(Val::U8(0)))),
+            ]),
+        )]);
+        let err = RecordFields::new(val, "record")
+            .unwrap()
+            .take::<Vec<Option<String>>>("points")
+            .err()
+            .unwrap();
+        assert_eq!(err.path().to_string(), "points[3]<some>");
+        assert_eq!(
+            err.path().segments(),
+            [
+                PathSegment::Field("points".to_string()),
+                PathSegment::Index(3),
+                PathSegment::Case("some".to_string()),
+            ]
+        );
+        assert_eq!((err.expected(), err.found()), ("string", "u8"));
+    }
+
+    #[test]
+    fn tuples_check_their_length() {
+        let (a, b) =
+            <(u8, String)>::from_val(Val::Tuple(vec![Val::U8(1), Val::String("b".to_string())]))
+                .unwrap();
+        assert_eq!((a, b.as_str()), (1, "b"));
+
+        let err = <(u8, u8)>::from_val(Val::Tuple(vec![Val::U8(1)]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "expected tuple of 2, found tuple");
+    }
+
+    /// Converts `value` to a `Val`, compares it with `expected` and converts it back.
+    ///
+    /// `Val` has no `PartialEq` because of its floats, so values are compared by their debug
+    /// representation.
+    fn assert_round_trips<T>(value: T, expected: Val)
+    where
+        T: Clone + std::fmt::Debug + PartialEq + Into<Val> + TryFrom<Val, Error = FromValError>,
+    {
+        let val: Val = value.clone().into();
+        assert_eq!(format!("{val:?}"), format!("{expected:?}"));
+        assert_eq!(T::try_from(val).unwrap(), value);
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        assert_round_trips('é', Val::Char('é'));
+        assert_round_trips("text".to_string(), Val::String("text".to_string()));
+        assert_round_trips(usize::MAX, Val::U64(usize::MAX as u64));
+        assert_round_trips(isize::MIN, Val::S64(isize::MIN as i64));
+    }
+
+    #[test]
+    fn containers_round_trip() {
+        assert_round_trips(vec![1u8, 2], Val::List(vec![Val::U8(1), Val::U8(2)]));
+        assert_round_trips(Vec::<u8>::new(), Val::List(vec![]));
+        assert_round_trips(Some(1u8), Val::Option(Some(Box::new(Val::U8(1)))));
+        assert_round_trips(None::<u8>, Val::Option(None));
+        assert_round_trips(
+            Some(vec![Some(1u8), None]),
+            Val::Option(Some(Box::new(Val::List(vec![
+                Val::Option(Some(Box::new(Val::U8(1)))),
+                Val::Option(None),
+            ])))),
+        );
+    }
+
+    #[test]
+    fn unit_result_cases_have_no_payload() {
+        assert_round_trips(Ok::<(), String>(()), Val::Result(Ok(None)));
+        assert_round_trips(
+            Err::<(), String>("bad".to_string()),
+            Val::Result(Err(Some(Box::new(Val::String("bad".to_string()))))),
+        );
+        assert_round_trips(Ok::<u8, ()>(1), Val::Result(Ok(Some(Box::new(Val::U8(1))))));
+        assert_round_trips(Err::<u8, ()>(()), Val::Result(Err(None)));
+
+        // A payload where `()` expects none is still a mismatch.
+        let err = Result::<(), String>::try_from(Val::Result(Ok(Some(Box::new(Val::U8(1))))))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "expected tuple of 0, found u8 at `<ok>`");
+    }
+
+    #[test]
+    fn twelve_tuples_round_trip() {
+        let value = (
+            1u8,
+            2i8,
+            3u16,
+            4i16,
+            5u32,
+            6i32,
+            7u64,
+            8i64,
+            9.5f32,
+            10.5f64,
+            'k',
+            "l".to_string(),
+        );
+        assert_round_trips(
+            value,
+            Val::Tuple(vec![
+                Val::U8(1),
+                Val::S8(2),
+                Val::U16(3),
+                Val::S16(4),
+                Val::U32(5),
+                Val::S32(6),
+                Val::U64(7),
+                Val::S64(8),
+                Val::Float32(9.5),
+                Val::Float64(10.5),
+                Val::Char('k'),
+                Val::String("l".to_string()),
+            ]),
+        );
+    }
+
+    #[test]
+    fn pointer_sized_integers_check_their_range() {
+        // Only 32-bit targets, such as the wasm32 guest, can't hold every 64-bit value.
+        let res = usize::try_from(Val::U64(u64::MAX));
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(res.unwrap(), usize::MAX);
+        } else {
+            let err = res.err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!("expected usize, found out-of-range {}", u64::MAX)
+            );
+        }
+        let res = isize::try_from(Val::S64(i64::MIN));
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(res.unwrap(), isize::MIN);
+        } else {
+            let err = res.err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!("expected isize, found out-of-range {}", i64::MIN)
+            );
+        }
+
+        let err = usize::try_from(Val::U32(1)).err().unwrap();
+        assert_eq!(err.to_string(), "expected u64, found u32");
+        let err = isize::try_from(Val::U64(1)).err().unwrap();
+        assert_eq!(err.to_string(), "expected s64, found u64");
+    }
+}
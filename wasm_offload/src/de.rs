@@ -0,0 +1,569 @@
+use serde::{
+    de::{self, DeserializeOwned, IntoDeserializer, Visitor},
+    forward_to_deserialize_any,
+};
+
+use crate::{PathSegment, SerdeError, Val};
+
+/// Deserializes a `T` out of a [`Val`], accepting the shapes produced by
+/// [`to_val`](crate::to_val).
+pub fn from_val<T: DeserializeOwned>(val: Val) -> Result<T, SerdeError> {
+    T::deserialize(ValDeserializer(val))
+}
+
+impl de::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerdeError::new(msg.to_string())
+    }
+}
+
+fn unexpected(val: &Val) -> de::Unexpected<'_> {
+    match val {
+        Val::Bool(v) => de::Unexpected::Bool(*v),
+        Val::S8(v) => de::Unexpected::Signed(*v as i64),
+        Val::S16(v) => de::Unexpected::Signed(*v as i64),
+        Val::S32(v) => de::Unexpected::Signed(*v as i64),
+        Val::S64(v) => de::Unexpected::Signed(*v),
+        Val::U8(v) => de::Unexpected::Unsigned(*v as u64),
+        Val::U16(v) => de::Unexpected::Unsigned(*v as u64),
+        Val::U32(v) => de::Unexpected::Unsigned(*v as u64),
+        Val::U64(v) => de::Unexpected::Unsigned(*v),
+        Val::Float32(v) => de::Unexpected::Float(*v as f64),
+        Val::Float64(v) => de::Unexpected::Float(*v),
+        Val::Char(v) => de::Unexpected::Char(*v),
+        Val::String(v) => de::Unexpected::Str(v),
+        Val::List(_) => de::Unexpected::Seq,
+        Val::Tuple(_) => de::Unexpected::Seq,
+        Val::Record(_) => de::Unexpected::Map,
+        Val::Variant(_, _) | Val::Enum(_) | Val::Result(_) => de::Unexpected::Enum,
+        Val::Option(_) => de::Unexpected::Option,
+        Val::Flags(_) => de::Unexpected::Other("flags"),
+    }
+}
+
+fn invalid_type(val: &Val, exp: &dyn de::Expected) -> SerdeError {
+    <SerdeError as de::Error>::invalid_type(unexpected(val), exp)
+}
+
+struct ValDeserializer(Val);
+
+impl<'de> IntoDeserializer<'de, SerdeError> for ValDeserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValDeserializer {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Val::Bool(v) => visitor.visit_bool(v),
+            Val::S8(v) => visitor.visit_i8(v),
+            Val::S16(v) => visitor.visit_i16(v),
+            Val::S32(v) => visitor.visit_i32(v),
+            Val::S64(v) => visitor.visit_i64(v),
+            Val::U8(v) => visitor.visit_u8(v),
+            Val::U16(v) => visitor.visit_u16(v),
+            Val::U32(v) => visitor.visit_u32(v),
+            Val::U64(v) => visitor.visit_u64(v),
+            Val::Float32(v) => visitor.visit_f32(v),
+            Val::Float64(v) => visitor.visit_f64(v),
+            Val::Char(v) => visitor.visit_char(v),
+            Val::String(v) => visitor.visit_string(v),
+            Val::List(items) | Val::Tuple(items) => visit_seq(items, visitor),
+            Val::Record(fields) => visitor.visit_map(RecordAccess::new(fields)),
+            Val::Option(None) => visitor.visit_none(),
+            Val::Option(Some(v)) => visitor
+                .visit_some(ValDeserializer(*v))
+                .map_err(|e| e.at(PathSegment::Case("some".to_string()))),
+            Val::Flags(names) => visit_seq(names.into_iter().map(Val::String).collect(), visitor),
+            val @ (Val::Variant(_, _) | Val::Enum(_) | Val::Result(_)) => {
+                visitor.visit_enum(EnumAccess::new(val))
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Val::Option(None) => visitor.visit_none(),
+            Val::Option(Some(v)) => visitor
+                .visit_some(ValDeserializer(*v))
+                .map_err(|e| e.at(PathSegment::Case("some".to_string()))),
+            other => Err(invalid_type(&other, &visitor)),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Val::Tuple(items) if items.is_empty() => visitor.visit_unit(),
+            other => Err(invalid_type(&other, &visitor)),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Val::List(items) => {
+                let mut bytes = Vec::with_capacity(items.len());
+                for (i, item) in items.into_iter().enumerate() {
+                    match item {
+                        Val::U8(b) => bytes.push(b),
+                        other => {
+                            return Err(invalid_type(&other, &"a byte").at(PathSegment::Index(i)))
+                        }
+                    }
+                }
+                visitor.visit_byte_buf(bytes)
+            }
+            other => Err(invalid_type(&other, &visitor)),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Val::List(entries) => visitor.visit_map(PairsAccess::new(entries)),
+            Val::Record(fields) => visitor.visit_map(RecordAccess::new(fields)),
+            other => Err(invalid_type(&other, &visitor)),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Val::String(case) => visitor.visit_enum(EnumAccess::new(Val::Enum(case))),
+            val @ (Val::Variant(_, _) | Val::Enum(_) | Val::Result(_)) => {
+                visitor.visit_enum(EnumAccess::new(val))
+            }
+            other => Err(invalid_type(&other, &visitor)),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+        seq tuple tuple_struct struct identifier ignored_any
+    }
+}
+
+fn visit_seq<'de, V: Visitor<'de>>(items: Vec<Val>, visitor: V) -> Result<V::Value, SerdeError> {
+    let len = items.len();
+    let mut access = SeqAccess {
+        items: items.into_iter(),
+        idx: 0,
+    };
+    let value = visitor.visit_seq(&mut access)?;
+    if access.idx == len {
+        Ok(value)
+    } else {
+        Err(<SerdeError as de::Error>::invalid_length(
+            len,
+            &"fewer elements",
+        ))
+    }
+}
+
+struct SeqAccess {
+    items: std::vec::IntoIter<Val>,
+    idx: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, SerdeError> {
+        let Some(item) = self.items.next() else {
+            return Ok(None);
+        };
+        let idx = self.idx;
+        self.idx += 1;
+        seed.deserialize(ValDeserializer(item))
+            .map(Some)
+            .map_err(|e| e.at(PathSegment::Index(idx)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+struct RecordAccess {
+    fields: std::vec::IntoIter<(String, Val)>,
+    value: Option<(String, Val)>,
+}
+
+impl RecordAccess {
+    fn new(fields: Vec<(String, Val)>) -> Self {
+        Self {
+            fields: fields.into_iter(),
+            value: None,
+        }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for RecordAccess {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, SerdeError> {
+        let Some((name, val)) = self.fields.next() else {
+            return Ok(None);
+        };
+        let key = seed.deserialize(name.as_str().into_deserializer())?;
+        self.value = Some((name, val));
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, SerdeError> {
+        let (name, val) = self
+            .value
+            .take()
+            .ok_or_else(|| <SerdeError as de::Error>::custom("record value without a key"))?;
+        seed.deserialize(ValDeserializer(val))
+            .map_err(|e| e.at(PathSegment::Field(name)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.fields.len())
+    }
+}
+
+/// Map access over a list of `(key, value)` tuples.
+struct PairsAccess {
+    entries: std::vec::IntoIter<Val>,
+    idx: usize,
+    value: Option<Val>,
+}
+
+impl PairsAccess {
+    fn new(entries: Vec<Val>) -> Self {
+        Self {
+            entries: entries.into_iter(),
+            idx: 0,
+            value: None,
+        }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for PairsAccess {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, SerdeError> {
+        let Some(entry) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.idx += 1;
+        let idx = self.idx - 1;
+        let (key, value) = match entry {
+            Val::Tuple(pair) if pair.len() == 2 => {
+                let mut pair = pair.into_iter();
+                (pair.next().unwrap(), pair.next().unwrap())
+            }
+            other => {
+                return Err(
+                    invalid_type(&other, &"a (key, value) tuple").at(PathSegment::Index(idx))
+                )
+            }
+        };
+        self.value = Some(value);
+        seed.deserialize(ValDeserializer(key))
+            .map(Some)
+            .map_err(|e| e.at(PathSegment::Index(0)).at(PathSegment::Index(idx)))
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, SerdeError> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| <SerdeError as de::Error>::custom("map value without a key"))?;
+        let idx = self.idx - 1;
+        seed.deserialize(ValDeserializer(value))
+            .map_err(|e| e.at(PathSegment::Index(1)).at(PathSegment::Index(idx)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumAccess {
+    case: String,
+    payload: Option<Val>,
+}
+
+impl EnumAccess {
+    fn new(val: Val) -> Self {
+        let (case, payload) = match val {
+            Val::Enum(case) => (case, None),
+            Val::Variant(case, payload) => (case, payload.map(|v| *v)),
+            // `result<_, E>` has no payload where serde expects `Ok(())`.
+            Val::Result(Ok(payload)) => ("Ok".to_string(), Some(unit_payload(payload))),
+            Val::Result(Err(payload)) => ("Err".to_string(), Some(unit_payload(payload))),
+            _ => unreachable!("only enum-like values are accessed as enums"),
+        };
+        Self { case, payload }
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = SerdeError;
+    type Variant = VariantAccess;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantAccess), SerdeError> {
+        let case = seed.deserialize(self.case.as_str().into_deserializer())?;
+        Ok((
+            case,
+            VariantAccess {
+                case: self.case,
+                payload: self.payload,
+            },
+        ))
+    }
+}
+
+fn unit_payload(payload: Option<Box<Val>>) -> Val {
+    payload.map(|v| *v).unwrap_or(Val::Tuple(vec![]))
+}
+
+struct VariantAccess {
+    case: String,
+    payload: Option<Val>,
+}
+
+impl VariantAccess {
+    fn payload(self, exp: &dyn de::Expected) -> Result<(ValDeserializer, String), SerdeError> {
+        match self.payload {
+            Some(val) => Ok((ValDeserializer(val), self.case)),
+            None => Err(<SerdeError as de::Error>::invalid_type(
+                de::Unexpected::UnitVariant,
+                exp,
+            )),
+        }
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        match self.payload {
+            None => Ok(()),
+            Some(Val::Tuple(items)) if items.is_empty() => Ok(()),
+            Some(other) => {
+                Err(invalid_type(&other, &"a unit variant").at(PathSegment::Case(self.case)))
+            }
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, SerdeError> {
+        let (de, case) = self.payload(&"a newtype variant")?;
+        seed.deserialize(de)
+            .map_err(|e| e.at(PathSegment::Case(case)))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        let (de, case) = self.payload(&visitor)?;
+        de::Deserializer::deserialize_seq(de, visitor).map_err(|e| e.at(PathSegment::Case(case)))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        let (de, case) = self.payload(&visitor)?;
+        de::Deserializer::deserialize_map(de, visitor).map_err(|e| e.at(PathSegment::Case(case)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::{from_val, to_val, PathSegment, Val};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct Point {
+        x: i32,
+        y_offset: Option<u8>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    enum Shape {
+        Circle(f32),
+        Rect { width: u32, height: u32 },
+        Empty,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Path {
+        points: Vec<Point>,
+    }
+
+    /// Serializes `value`, compares the result with `expected` and deserializes it again.
+    ///
+    /// `Val` has no `PartialEq` because of its floats, so values are compared by their debug
+    /// representation.
+    fn assert_round_trips<T>(value: T, expected: Val)
+    where
+        T: std::fmt::Debug + PartialEq + Serialize + serde::de::DeserializeOwned,
+    {
+        let val = to_val(&value).unwrap();
+        assert_eq!(format!("{val:?}"), format!("{expected:?}"));
+        assert_eq!(from_val::<T>(val).unwrap(), value);
+    }
+
+    #[test]
+    fn structs_become_records() {
+        assert_round_trips(
+            Point {
+                x: -1,
+                y_offset: Some(2),
+            },
+            Val::Record(vec![
+                ("x".to_string(), Val::S32(-1)),
+                (
+                    "y-offset".to_string(),
+                    Val::Option(Some(Box::new(Val::U8(2)))),
+                ),
+            ]),
+        );
+    }
+
+    #[test]
+    fn enums_become_variants_or_enums() {
+        assert_round_trips(
+            Shape::Circle(1.5),
+            Val::Variant("circle".to_string(), Some(Box::new(Val::Float32(1.5)))),
+        );
+        assert_round_trips(
+            Shape::Rect {
+                width: 2,
+                height: 3,
+            },
+            Val::Variant(
+                "rect".to_string(),
+                Some(Box::new(Val::Record(vec![
+                    ("width".to_string(), Val::U32(2)),
+                    ("height".to_string(), Val::U32(3)),
+                ]))),
+            ),
+        );
+        assert_round_trips(Shape::Empty, Val::Enum("empty".to_string()));
+    }
+
+    #[test]
+    fn unit_results_have_no_payload() {
+        assert_round_trips(Ok::<(), String>(()), Val::Result(Ok(None)));
+        assert_round_trips(
+            Err::<(), String>("bad".to_string()),
+            Val::Result(Err(Some(Box::new(Val::String("bad".to_string()))))),
+        );
+        assert_round_trips(Ok::<u8, ()>(1), Val::Result(Ok(Some(Box::new(Val::U8(1))))));
+        assert_round_trips(Err::<u8, ()>(()), Val::Result(Err(None)));
+    }
+
+    #[test]
+    fn maps_become_lists_of_tuples() {
+        let map = BTreeMap::from([("a".to_string(), 1u8), ("b".to_string(), 2)]);
+        assert_round_trips(
+            map,
+            Val::List(vec![
+                Val::Tuple(vec![Val::String("a".to_string()), Val::U8(1)]),
+                Val::Tuple(vec![Val::String("b".to_string()), Val::U8(2)]),
+            ]),
+        );
+    }
+
+    #[test]
+    fn errors_name_the_path_to_the_failing_field() {
+        let point = |x: Val| {
+            Val::Record(vec![
+                ("x".to_string(), x),
+                ("y-offset".to_string(), Val::Option(None)),
+            ])
+        };
+        let val = Val::Record(vec![(
+            "points".to_string(),
+            Val::List(vec![
+                point(Val::S32(1)),
+                point(Val::String("two".to_string())),
+            ]),
+        )]);
+        let err = from_val::<Path>(val).unwrap_err();
+        assert_eq!(
+            err.path().segments(),
+            [
+                PathSegment::Field("points".to_string()),
+                PathSegment::Index(1),
+                PathSegment::Field("x".to_string()),
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            "invalid type: string \"two\", expected i32 at `points[1].x`"
+        );
+
+        let val = Val::Variant(
+            "rect".to_string(),
+            Some(Box::new(Val::Record(vec![
+                ("width".to_string(), Val::U32(2)),
+                ("height".to_string(), Val::Bool(true)),
+            ]))),
+        );
+        let err = from_val::<Shape>(val).unwrap_err();
+        assert_eq!(err.path().to_string(), "<rect>.height");
+    }
+}
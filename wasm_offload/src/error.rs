@@ -0,0 +1,94 @@
+use thiserror::Error;
+
+use crate::{FromValError, TypeMismatch};
+#[cfg(feature = "serde")]
+use crate::{PathSegment, ValPath};
+
+/// The error returned by functions generated with [`offload`](crate::offload).
+#[derive(Error, Debug)]
+pub enum OffloadError {
+    #[error("offload target is poisoned")]
+    Poisoned,
+    #[error("offload target error: {0}")]
+    Target(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("`{function}` takes {expected} arguments, but {found} were passed")]
+    ArgumentCount {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    /// `param` is the name of the parameter, or its position if the target only knows the
+    /// parameter types.
+    #[error("invalid argument `{param}` for `{function}`: {mismatch}")]
+    ArgumentType {
+        function: String,
+        param: String,
+        #[source]
+        mismatch: TypeMismatch,
+    },
+    #[error("guest panicked at {file}:{line}: {message}")]
+    GuestPanic {
+        message: String,
+        file: String,
+        line: u32,
+    },
+    #[error("`{0}` did not return a value")]
+    MissingResult(String),
+    #[error("invalid result from `{function}`: {source}")]
+    InvalidResult {
+        function: String,
+        #[source]
+        source: FromValError,
+    },
+}
+
+impl OffloadError {
+    pub fn target(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Self::Target(Box::new(err))
+    }
+}
+
+/// An error while converting between a serde data structure and a [`Val`](crate::Val).
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerdeError {
+    path: ValPath,
+    message: String,
+}
+
+#[cfg(feature = "serde")]
+impl SerdeError {
+    pub(crate) fn new(message: String) -> Self {
+        Self {
+            path: ValPath::new(),
+            message,
+        }
+    }
+
+    pub(crate) fn at(mut self, segment: PathSegment) -> Self {
+        self.path.prepend(segment);
+        self
+    }
+
+    pub fn path(&self) -> &ValPath {
+        &self.path
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{} at `{}`", self.message, self.path)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for SerdeError {}
@@ -1,4 +1,30 @@
-pub use wasm_offload_procmacro::offload;
+use std::{future::Future, sync::Mutex};
+
+pub mod abi;
+mod convert;
+#[cfg(feature = "serde")]
+mod de;
+mod error;
+mod path;
+#[cfg(feature = "serde")]
+mod ser;
+mod val_type;
+mod wave;
+mod wire;
+
+pub use convert::{FromVal, FromValError, RecordFields};
+#[cfg(feature = "serde")]
+pub use de::from_val;
+pub use error::OffloadError;
+#[cfg(feature = "serde")]
+pub use error::SerdeError;
+pub use path::{PathSegment, ValPath};
+#[cfg(feature = "serde")]
+pub use ser::to_val;
+pub use val_type::{type_check_args, HasValType, TypeMismatch, ValType};
+pub use wasm_offload_procmacro::{offload, offload_module, FromVal, HasValType, IntoVal};
+pub use wave::WaveError;
+pub use wire::{DecodeLimits, WireError};
 
 #[derive(Clone, Debug)]
 pub enum Val {
@@ -26,6 +52,34 @@ pub enum Val {
 }
 
 impl Val {
+    /// The WIT name of the kind of this value, e.g. `s32` or `record`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Val::Bool(_) => "bool",
+            Val::S8(_) => "s8",
+            Val::U8(_) => "u8",
+            Val::S16(_) => "s16",
+            Val::U16(_) => "u16",
+            Val::S32(_) => "s32",
+            Val::U32(_) => "u32",
+            Val::S64(_) => "s64",
+            Val::U64(_) => "u64",
+            Val::Float32(_) => "f32",
+            Val::Float64(_) => "f64",
+            Val::Char(_) => "char",
+            Val::String(_) => "string",
+            Val::List(_) => "list",
+            Val::Record(_) => "record",
+            Val::Tuple(_) => "tuple",
+            Val::Variant(_, _) => "variant",
+            Val::Enum(_) => "enum",
+            Val::Option(_) => "option",
+            Val::Result(_) => "result",
+            Val::Flags(_) => "flags",
+        }
+    }
+
+    #[deprecated(note = "use `FromVal` or `i32::try_from`, which do not panic")]
     pub fn into_i32(self) -> i32 {
         match self {
             Val::S32(v) => v,
@@ -88,16 +142,168 @@ impl From<u64> for Val {
     }
 }
 
+impl From<usize> for Val {
+    fn from(value: usize) -> Self {
+        Self::U64(value as u64)
+    }
+}
+
+impl From<isize> for Val {
+    fn from(value: isize) -> Self {
+        Self::S64(value as i64)
+    }
+}
+
+impl From<f32> for Val {
+    fn from(value: f32) -> Self {
+        Self::Float32(value)
+    }
+}
+
+impl From<f64> for Val {
+    fn from(value: f64) -> Self {
+        Self::Float64(value)
+    }
+}
+
+impl From<char> for Val {
+    fn from(value: char) -> Self {
+        Self::Char(value)
+    }
+}
+
+impl From<String> for Val {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<&str> for Val {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+/// A module registered with an offload target through `load_module`.
+///
+/// Handles are only meaningful to the target that issued them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ModuleHandle(u64);
+
+impl ModuleHandle {
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+}
+
 pub trait OffloadTarget {
-    type Error;
+    type Error: std::error::Error + Send + Sync + 'static;
 
     fn initialize(&mut self) -> Result<(), Self::Error>;
 
+    /// Compiles `module` so that it can be called through the returned handle. Loading the same
+    /// bytes again may return the existing handle.
+    fn load_module(&mut self, module: &[u8]) -> Result<ModuleHandle, Self::Error>;
+
+    fn call(
+        &mut self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
+    ) -> Result<Option<Val>, Self::Error>;
+
+    /// Loads `module` and calls `name` in it.
     fn call_function(
         &mut self,
         module: &[u8],
         name: &str,
         args: &[Val],
         returns: bool,
+    ) -> Result<Option<Val>, Self::Error> {
+        let module = self.load_module(module)?;
+        self.call(module, name, args, returns)
+    }
+}
+
+/// An offload target that can be called from several threads at once.
+///
+/// This is what the synchronous wrappers generated by `#[offload]` call. Any [`OffloadTarget`]
+/// can be shared by putting it in a [`Mutex`], which serializes all calls; targets that can
+/// run calls in parallel implement this trait directly.
+pub trait SharedOffloadTarget: Sync {
+    type Error: Into<OffloadError>;
+
+    fn load_module(&self, module: &[u8]) -> Result<ModuleHandle, Self::Error>;
+
+    fn call(
+        &self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
     ) -> Result<Option<Val>, Self::Error>;
 }
+
+impl<T: OffloadTarget + Send> SharedOffloadTarget for Mutex<T> {
+    type Error = OffloadError;
+
+    fn load_module(&self, module: &[u8]) -> Result<ModuleHandle, OffloadError> {
+        let mut target = self.lock().map_err(|_| OffloadError::Poisoned)?;
+        target.load_module(module).map_err(OffloadError::target)
+    }
+
+    fn call(
+        &self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
+    ) -> Result<Option<Val>, OffloadError> {
+        let mut target = self.lock().map_err(|_| OffloadError::Poisoned)?;
+        target
+            .call(module, name, args, returns)
+            .map_err(OffloadError::target)
+    }
+}
+
+/// An [`OffloadTarget`] that runs guest code without blocking the calling thread.
+///
+/// Methods take `&self` so that a single target can serve concurrent calls, e.g. from a
+/// `static` shared between tasks.
+pub trait AsyncOffloadTarget: Sync {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn initialize(&self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn load_module(
+        &self,
+        module: &[u8],
+    ) -> impl Future<Output = Result<ModuleHandle, Self::Error>> + Send;
+
+    fn call(
+        &self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
+    ) -> impl Future<Output = Result<Option<Val>, Self::Error>> + Send;
+
+    /// Loads `module` and calls `name` in it.
+    fn call_function(
+        &self,
+        module: &[u8],
+        name: &str,
+        args: &[Val],
+        returns: bool,
+    ) -> impl Future<Output = Result<Option<Val>, Self::Error>> + Send {
+        async move {
+            let module = self.load_module(module).await?;
+            self.call(module, name, args, returns).await
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+
+/// One step into a nested [`Val`](crate::Val).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathSegment {
+    /// A named field of a record.
+    Field(String),
+    /// An element of a list or tuple.
+    Index(usize),
+    /// The payload of a variant, option or result case.
+    Case(String),
+}
+
+/// The location of a value inside a larger [`Val`](crate::Val), e.g. `p1.x` or `points[3]`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValPath(Vec<PathSegment>);
+
+impl ValPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.0
+    }
+
+    /// Adds `segment` as the outermost step of the path.
+    pub fn prepend(&mut self, segment: PathSegment) {
+        self.0.insert(0, segment);
+    }
+
+    /// Adds `segment` as the innermost step of the path.
+    pub fn push(&mut self, segment: PathSegment) {
+        self.0.push(segment);
+    }
+
+    pub fn pop(&mut self) -> Option<PathSegment> {
+        self.0.pop()
+    }
+}
+
+impl fmt::Display for ValPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Field(name) if i == 0 => write!(f, "{name}")?,
+                PathSegment::Field(name) => write!(f, ".{name}")?,
+                PathSegment::Index(idx) => write!(f, "[{idx}]")?,
+                PathSegment::Case(name) => write!(f, "<{name}>")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+use serde::{ser, Serialize};
+
+use crate::{PathSegment, SerdeError, Val};
+
+/// Serializes `value` into a [`Val`].
+///
+/// Structs become records, sequences become lists and maps become lists of `(key, value)`
+/// tuples. Unit variants become enums and all other variants become variants, except for
+/// `Result`, which maps to [`Val::Result`]. Field and case names are taken verbatim from serde,
+/// so use `#[serde(rename_all = "kebab-case")]` where the names need to match a WIT interface.
+pub fn to_val<T: Serialize + ?Sized>(value: &T) -> Result<Val, SerdeError> {
+    value.serialize(ValSerializer)
+}
+
+impl ser::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerdeError::new(msg.to_string())
+    }
+}
+
+struct ValSerializer;
+
+impl ser::Serializer for ValSerializer {
+    type Ok = Val;
+    type Error = SerdeError;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeRecord;
+    type SerializeStructVariant = SerializeRecord;
+
+    fn serialize_bool(self, v: bool) -> Result<Val, SerdeError> {
+        Ok(Val::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Val, SerdeError> {
+        Ok(Val::S8(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Val, SerdeError> {
+        Ok(Val::S16(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Val, SerdeError> {
+        Ok(Val::S32(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Val, SerdeError> {
+        Ok(Val::S64(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Val, SerdeError> {
+        Ok(Val::U8(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Val, SerdeError> {
+        Ok(Val::U16(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Val, SerdeError> {
+        Ok(Val::U32(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Val, SerdeError> {
+        Ok(Val::U64(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Val, SerdeError> {
+        Ok(Val::Float32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Val, SerdeError> {
+        Ok(Val::Float64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Val, SerdeError> {
+        Ok(Val::Char(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Val, SerdeError> {
+        Ok(Val::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Val, SerdeError> {
+        Ok(Val::List(v.iter().copied().map(Val::U8).collect()))
+    }
+
+    fn serialize_none(self) -> Result<Val, SerdeError> {
+        Ok(Val::Option(None))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Val, SerdeError> {
+        let val = value
+            .serialize(ValSerializer)
+            .map_err(|e| e.at(PathSegment::Case("some".to_string())))?;
+        Ok(Val::Option(Some(Box::new(val))))
+    }
+
+    fn serialize_unit(self) -> Result<Val, SerdeError> {
+        Ok(Val::Tuple(vec![]))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Val, SerdeError> {
+        Ok(Val::Tuple(vec![]))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Val, SerdeError> {
+        Ok(Val::Enum(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Val, SerdeError> {
+        value.serialize(ValSerializer)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Val, SerdeError> {
+        let val = value
+            .serialize(ValSerializer)
+            .map_err(|e| e.at(PathSegment::Case(variant.to_string())))?;
+        let is_unit = matches!(&val, Val::Tuple(items) if items.is_empty());
+        let payload = Some(Box::new(val));
+        Ok(match (name, variant) {
+            // `Result<(), E>` maps to `result<_, E>`, whose cases have no payload.
+            ("Result", "Ok") => Val::Result(Ok(payload.filter(|_| !is_unit))),
+            ("Result", "Err") => Val::Result(Err(payload.filter(|_| !is_unit))),
+            _ => Val::Variant(variant.to_string(), payload),
+        })
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, SerdeError> {
+        Ok(SerializeList::new(len.unwrap_or(0), None))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, SerdeError> {
+        Ok(SerializeList::new(len, None))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, SerdeError> {
+        Ok(SerializeList::new(len, None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, SerdeError> {
+        Ok(SerializeList::new(len, Some(variant)))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap, SerdeError> {
+        Ok(SerializeMap {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeRecord, SerdeError> {
+        Ok(SerializeRecord::new(len, None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeRecord, SerdeError> {
+        Ok(SerializeRecord::new(len, Some(variant)))
+    }
+}
+
+/// Wraps `val` in a variant case if the serializer was started for one.
+fn wrap_variant(variant: Option<&'static str>, val: Val) -> Val {
+    match variant {
+        Some(case) => Val::Variant(case.to_string(), Some(Box::new(val))),
+        None => val,
+    }
+}
+
+/// Adds the variant case to the path of `err` if the serializer was started for one.
+fn at_variant(variant: Option<&'static str>, err: SerdeError) -> SerdeError {
+    match variant {
+        Some(case) => err.at(PathSegment::Case(case.to_string())),
+        None => err,
+    }
+}
+
+struct SerializeList {
+    items: Vec<Val>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeList {
+    fn new(len: usize, variant: Option<&'static str>) -> Self {
+        Self {
+            items: Vec::with_capacity(len),
+            variant,
+        }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let idx = self.items.len();
+        let val = value
+            .serialize(ValSerializer)
+            .map_err(|e| at_variant(self.variant, e.at(PathSegment::Index(idx))))?;
+        self.items.push(val);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Val;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Val, SerdeError> {
+        Ok(Val::List(self.items))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Val;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Val, SerdeError> {
+        Ok(Val::Tuple(self.items))
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Val;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Val, SerdeError> {
+        Ok(Val::Tuple(self.items))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = Val;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Val, SerdeError> {
+        Ok(wrap_variant(self.variant, Val::Tuple(self.items)))
+    }
+}
+
+struct SerializeMap {
+    entries: Vec<Val>,
+    key: Option<Val>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Val;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        let idx = self.entries.len();
+        let key = key
+            .serialize(ValSerializer)
+            .map_err(|e| e.at(PathSegment::Index(0)).at(PathSegment::Index(idx)))?;
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let idx = self.entries.len();
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| <SerdeError as ser::Error>::custom("map value without a key"))?;
+        let value = value
+            .serialize(ValSerializer)
+            .map_err(|e| e.at(PathSegment::Index(1)).at(PathSegment::Index(idx)))?;
+        self.entries.push(Val::Tuple(vec![key, value]));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Val, SerdeError> {
+        Ok(Val::List(self.entries))
+    }
+}
+
+struct SerializeRecord {
+    fields: Vec<(String, Val)>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeRecord {
+    fn new(len: usize, variant: Option<&'static str>) -> Self {
+        Self {
+            fields: Vec::with_capacity(len),
+            variant,
+        }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), SerdeError> {
+        let val = value
+            .serialize(ValSerializer)
+            .map_err(|e| at_variant(self.variant, e.at(PathSegment::Field(key.to_string()))))?;
+        self.fields.push((key.to_string(), val));
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for SerializeRecord {
+    type Ok = Val;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Val, SerdeError> {
+        Ok(Val::Record(self.fields))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeRecord {
+    type Ok = Val;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Val, SerdeError> {
+        Ok(wrap_variant(self.variant, Val::Record(self.fields)))
+    }
+}
@@ -0,0 +1,482 @@
+use std::fmt;
+
+use crate::{
+    path::{PathSegment, ValPath},
+    OffloadError, Val,
+};
+
+/// The type of a [`Val`], following the WIT type grammar.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValType {
+    Bool,
+    S8,
+    U8,
+    S16,
+    U16,
+    S32,
+    U32,
+    S64,
+    U64,
+    Float32,
+    Float64,
+    Char,
+    String,
+    List(Box<ValType>),
+    Record(Vec<(String, ValType)>),
+    Tuple(Vec<ValType>),
+    Variant(Vec<(String, Option<ValType>)>),
+    Enum(Vec<String>),
+    Option(Box<ValType>),
+    Result {
+        ok: Option<Box<ValType>>,
+        err: Option<Box<ValType>>,
+    },
+    Flags(Vec<String>),
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn list<T>(
+            f: &mut fmt::Formatter<'_>,
+            items: &[T],
+            mut item: impl FnMut(&mut fmt::Formatter<'_>, &T) -> fmt::Result,
+        ) -> fmt::Result {
+            for (i, t) in items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                item(f, t)?;
+            }
+            Ok(())
+        }
+
+        match self {
+            ValType::Bool => write!(f, "bool"),
+            ValType::S8 => write!(f, "s8"),
+            ValType::U8 => write!(f, "u8"),
+            ValType::S16 => write!(f, "s16"),
+            ValType::U16 => write!(f, "u16"),
+            ValType::S32 => write!(f, "s32"),
+            ValType::U32 => write!(f, "u32"),
+            ValType::S64 => write!(f, "s64"),
+            ValType::U64 => write!(f, "u64"),
+            ValType::Float32 => write!(f, "f32"),
+            ValType::Float64 => write!(f, "f64"),
+            ValType::Char => write!(f, "char"),
+            ValType::String => write!(f, "string"),
+            ValType::List(ty) => write!(f, "list<{ty}>"),
+            ValType::Record(fields) => {
+                write!(f, "record {{ ")?;
+                list(f, fields, |f, (name, ty)| write!(f, "{name}: {ty}"))?;
+                write!(f, " }}")
+            }
+            ValType::Tuple(tys) => {
+                write!(f, "tuple<")?;
+                list(f, tys, |f, ty| write!(f, "{ty}"))?;
+                write!(f, ">")
+            }
+            ValType::Variant(cases) => {
+                write!(f, "variant {{ ")?;
+                list(f, cases, |f, (name, ty)| match ty {
+                    Some(ty) => write!(f, "{name}({ty})"),
+                    None => write!(f, "{name}"),
+                })?;
+                write!(f, " }}")
+            }
+            ValType::Enum(cases) => {
+                write!(f, "enum {{ ")?;
+                list(f, cases, |f, name| write!(f, "{name}"))?;
+                write!(f, " }}")
+            }
+            ValType::Option(ty) => write!(f, "option<{ty}>"),
+            ValType::Result { ok, err } => match (ok, err) {
+                (None, None) => write!(f, "result"),
+                (Some(ok), None) => write!(f, "result<{ok}>"),
+                (None, Some(err)) => write!(f, "result<_, {err}>"),
+                (Some(ok), Some(err)) => write!(f, "result<{ok}, {err}>"),
+            },
+            ValType::Flags(names) => {
+                write!(f, "flags {{ ")?;
+                list(f, names, |f, name| write!(f, "{name}"))?;
+                write!(f, " }}")
+            }
+        }
+    }
+}
+
+/// A [`Val`] did not match the [`ValType`] it was checked against.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeMismatch {
+    path: ValPath,
+    expected: String,
+    found: String,
+}
+
+impl TypeMismatch {
+    fn new(expected: impl fmt::Display, found: impl Into<String>) -> Self {
+        Self {
+            path: ValPath::new(),
+            expected: expected.to_string(),
+            found: found.into(),
+        }
+    }
+
+    fn at(mut self, segment: PathSegment) -> Self {
+        self.path.prepend(segment);
+        self
+    }
+
+    pub fn path(&self) -> &ValPath {
+        &self.path
+    }
+
+    pub fn expected(&self) -> &str {
+        &self.expected
+    }
+
+    pub fn found(&self) -> &str {
+        &self.found
+    }
+}
+
+impl fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)?;
+        if !self.path.is_empty() {
+            write!(f, " at `{}`", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TypeMismatch {}
+
+impl Val {
+    /// Checks that this value is a valid instance of `ty`.
+    pub fn type_check(&self, ty: &ValType) -> Result<(), TypeMismatch> {
+        let payload = |val: &Option<Box<Val>>, ty: Option<&ValType>, case: &str| {
+            let res = match (val, ty) {
+                (None, None) => Ok(()),
+                (Some(val), Some(ty)) => val.type_check(ty),
+                (Some(val), None) => Err(TypeMismatch::new("no payload", val.kind())),
+                (None, Some(ty)) => Err(TypeMismatch::new(ty, "no payload")),
+            };
+            res.map_err(|e| e.at(PathSegment::Case(case.to_string())))
+        };
+
+        match (self, ty) {
+            (Val::Bool(_), ValType::Bool)
+            | (Val::S8(_), ValType::S8)
+            | (Val::U8(_), ValType::U8)
+            | (Val::S16(_), ValType::S16)
+            | (Val::U16(_), ValType::U16)
+            | (Val::S32(_), ValType::S32)
+            | (Val::U32(_), ValType::U32)
+            | (Val::S64(_), ValType::S64)
+            | (Val::U64(_), ValType::U64)
+            | (Val::Float32(_), ValType::Float32)
+            | (Val::Float64(_), ValType::Float64)
+            | (Val::Char(_), ValType::Char)
+            | (Val::String(_), ValType::String) => Ok(()),
+            (Val::List(items), ValType::List(ty)) => {
+                items.iter().enumerate().try_for_each(|(i, item)| {
+                    item.type_check(ty).map_err(|e| e.at(PathSegment::Index(i)))
+                })
+            }
+            (Val::Record(fields), ValType::Record(tys)) => {
+                for (i, (name, ty)) in tys.iter().enumerate() {
+                    match fields.get(i) {
+                        Some((n, val)) if n == name => val
+                            .type_check(ty)
+                            .map_err(|e| e.at(PathSegment::Field(name.clone())))?,
+                        Some((n, _)) => {
+                            return Err(TypeMismatch::new(
+                                format!("field `{name}`"),
+                                format!("field `{n}`"),
+                            ))
+                        }
+                        None => {
+                            return Err(TypeMismatch::new(format!("field `{name}`"), "nothing"))
+                        }
+                    }
+                }
+                match fields.get(tys.len()) {
+                    Some((n, _)) => Err(TypeMismatch::new(ty, format!("extra field `{n}`"))),
+                    None => Ok(()),
+                }
+            }
+            (Val::Tuple(items), ValType::Tuple(tys)) => {
+                if items.len() != tys.len() {
+                    return Err(TypeMismatch::new(ty, format!("tuple of {}", items.len())));
+                }
+                items
+                    .iter()
+                    .zip(tys)
+                    .enumerate()
+                    .try_for_each(|(i, (item, ty))| {
+                        item.type_check(ty).map_err(|e| e.at(PathSegment::Index(i)))
+                    })
+            }
+            (Val::Variant(case, val), ValType::Variant(cases)) => {
+                match cases.iter().find(|(name, _)| name == case) {
+                    Some((_, ty)) => payload(val, ty.as_ref(), case),
+                    None => Err(TypeMismatch::new(ty, format!("case `{case}`"))),
+                }
+            }
+            (Val::Enum(case), ValType::Enum(cases)) => {
+                if cases.contains(case) {
+                    Ok(())
+                } else {
+                    Err(TypeMismatch::new(ty, format!("case `{case}`")))
+                }
+            }
+            (Val::Option(val), ValType::Option(ty)) => match val {
+                Some(val) => val
+                    .type_check(ty)
+                    .map_err(|e| e.at(PathSegment::Case("some".to_string()))),
+                None => Ok(()),
+            },
+            (Val::Result(Ok(val)), ValType::Result { ok, .. }) => payload(val, ok.as_deref(), "ok"),
+            (Val::Result(Err(val)), ValType::Result { err, .. }) => {
+                payload(val, err.as_deref(), "err")
+            }
+            (Val::Flags(names), ValType::Flags(flags)) => {
+                match names.iter().find(|name| !flags.contains(name)) {
+                    Some(name) => Err(TypeMismatch::new(ty, format!("flag `{name}`"))),
+                    None => Ok(()),
+                }
+            }
+            (val, ty) => Err(TypeMismatch::new(ty, val.kind())),
+        }
+    }
+}
+
+/// Rust types with a fixed [`ValType`].
+pub trait HasValType {
+    fn val_type() -> ValType;
+}
+
+macro_rules! impl_has_val_type {
+    ($($ty:ty => $variant:ident;)*) => {
+        $(
+            impl HasValType for $ty {
+                fn val_type() -> ValType {
+                    ValType::$variant
+                }
+            }
+        )*
+    };
+}
+
+impl_has_val_type! {
+    bool => Bool;
+    i8 => S8;
+    u8 => U8;
+    i16 => S16;
+    u16 => U16;
+    i32 => S32;
+    u32 => U32;
+    i64 => S64;
+    u64 => U64;
+    isize => S64;
+    usize => U64;
+    f32 => Float32;
+    f64 => Float64;
+    char => Char;
+    String => String;
+}
+
+impl<T: HasValType> HasValType for Vec<T> {
+    fn val_type() -> ValType {
+        ValType::List(Box::new(T::val_type()))
+    }
+}
+
+impl<T: HasValType> HasValType for Option<T> {
+    fn val_type() -> ValType {
+        ValType::Option(Box::new(T::val_type()))
+    }
+}
+
+/// The payload type of a result case, where `()` means no payload.
+fn payload_type<T: HasValType>() -> Option<Box<ValType>> {
+    match T::val_type() {
+        ValType::Tuple(tys) if tys.is_empty() => None,
+        ty => Some(Box::new(ty)),
+    }
+}
+
+impl<T: HasValType, E: HasValType> HasValType for Result<T, E> {
+    fn val_type() -> ValType {
+        ValType::Result {
+            ok: payload_type::<T>(),
+            err: payload_type::<E>(),
+        }
+    }
+}
+
+macro_rules! impl_tuple_has_val_type {
+    ($($ty:ident),*) => {
+        impl<$($ty: HasValType),*> HasValType for ($($ty,)*) {
+            fn val_type() -> ValType {
+                ValType::Tuple(vec![$($ty::val_type()),*])
+            }
+        }
+    };
+}
+
+impl_tuple_has_val_type!();
+impl_tuple_has_val_type!(A);
+impl_tuple_has_val_type!(A, B);
+impl_tuple_has_val_type!(A, B, C);
+impl_tuple_has_val_type!(A, B, C, D);
+impl_tuple_has_val_type!(A, B, C, D, E);
+impl_tuple_has_val_type!(A, B, C, D, E, F);
+impl_tuple_has_val_type!(A, B, C, D, E, F, G);
+impl_tuple_has_val_type!(A, B, C, D, E, F, G, H);
+impl_tuple_has_val_type!(A, B, C, D, E, F, G, H, I);
+impl_tuple_has_val_type!(A, B, C, D, E, F, G, H, I, J);
+impl_tuple_has_val_type!(A, B, C, D, E, F, G, H, I, J, K);
+impl_tuple_has_val_type!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+/// Checks the arguments of a call to `function` against its parameter types.
+pub fn type_check_args(
+    function: &str,
+    params: &[(&str, ValType)],
+    args: &[Val],
+) -> Result<(), OffloadError> {
+    if params.len() != args.len() {
+        return Err(OffloadError::ArgumentCount {
+            function: function.to_string(),
+            expected: params.len(),
+            found: args.len(),
+        });
+    }
+    for ((name, ty), arg) in params.iter().zip(args) {
+        arg.type_check(ty)
+            .map_err(|mismatch| OffloadError::ArgumentType {
+                function: function.to_string(),
+                param: name.to_string(),
+                mismatch,
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point_type() -> ValType {
+        ValType::Record(vec![
+            ("x".to_string(), ValType::S32),
+            ("y".to_string(), ValType::S32),
+        ])
+    }
+
+    fn point(x: Val, y: Val) -> Val {
+        Val::Record(vec![("x".to_string(), x), ("y".to_string(), y)])
+    }
+
+    #[test]
+    fn matching_values_pass() {
+        let ty = ValType::List(Box::new(point_type()));
+        let val = Val::List(vec![point(Val::S32(1), Val::S32(2))]);
+        val.type_check(&ty).unwrap();
+
+        let ty = ValType::Result {
+            ok: None,
+            err: Some(Box::new(ValType::String)),
+        };
+        Val::Result(Ok(None)).type_check(&ty).unwrap();
+        Val::Result(Err(Some(Box::new(Val::String("bad".to_string())))))
+            .type_check(&ty)
+            .unwrap();
+    }
+
+    #[test]
+    fn mismatches_name_the_path_to_the_failing_value() {
+        let ty = ValType::List(Box::new(point_type()));
+        let val = Val::List(vec![
+            point(Val::S32(1), Val::S32(2)),
+            point(Val::S32(3), Val::U8(4)),
+        ]);
+        let err = val.type_check(&ty).unwrap_err();
+        assert_eq!(
+            err.path().segments(),
+            [PathSegment::Index(1), PathSegment::Field("y".to_string())]
+        );
+        assert_eq!((err.expected(), err.found()), ("s32", "u8"));
+        assert_eq!(err.to_string(), "expected s32, found u8 at `[1].y`");
+
+        let ty = ValType::Option(Box::new(ValType::Variant(vec![(
+            "circle".to_string(),
+            Some(ValType::Float32),
+        )])));
+        let val = Val::Option(Some(Box::new(Val::Variant("circle".to_string(), None))));
+        let err = val.type_check(&ty).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected f32, found no payload at `<some><circle>`"
+        );
+    }
+
+    #[test]
+    fn records_and_tuples_check_their_shape() {
+        let err = point(Val::S32(1), Val::S32(2))
+            .type_check(&ValType::Record(vec![("x".to_string(), ValType::S32)]))
+            .unwrap_err();
+        assert_eq!(err.found(), "extra field `y`");
+
+        let err = Val::Record(vec![("y".to_string(), Val::S32(2))])
+            .type_check(&point_type())
+            .unwrap_err();
+        assert_eq!((err.expected(), err.found()), ("field `x`", "field `y`"));
+
+        let err = Val::Tuple(vec![Val::U8(1)])
+            .type_check(&ValType::Tuple(vec![ValType::U8, ValType::U8]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "expected tuple<u8, u8>, found tuple of 1");
+
+        let err = Val::Enum("green".to_string())
+            .type_check(&ValType::Enum(vec!["red".to_string()]))
+            .unwrap_err();
+        assert_eq!(err.found(), "case `green`");
+    }
+
+    #[test]
+    fn arguments_are_checked_against_the_parameters() {
+        let params = [("a", ValType::U32), ("p", point_type())];
+        type_check_args(
+            "f",
+            &params,
+            &[Val::U32(1), point(Val::S32(1), Val::S32(2))],
+        )
+        .unwrap();
+
+        let err = type_check_args("f", &params, &[Val::U32(1)]).unwrap_err();
+        assert!(matches!(
+            err,
+            OffloadError::ArgumentCount {
+                expected: 2,
+                found: 1,
+                ..
+            }
+        ));
+
+        let args = [Val::U32(1), point(Val::S32(1), Val::Bool(true))];
+        let err = type_check_args("f", &params, &args).unwrap_err();
+        let OffloadError::ArgumentType {
+            param, mismatch, ..
+        } = &err
+        else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(param, "p");
+        assert_eq!(mismatch.path().to_string(), "y");
+        assert_eq!(
+            err.to_string(),
+            "invalid argument `p` for `f`: expected s32, found bool at `y`"
+        );
+    }
+}
@@ -0,0 +1,656 @@
+//! The WebAssembly Value Encoding (WAVE), a human-readable text format for [`Val`].
+//!
+//! `Display` for [`Val`] produces WAVE and [`Val::parse_wave`] reads it back, guided by the
+//! expected [`ValType`]:
+//!
+//! ```text
+//! 42, -1.5, nan, 'x', "hello\n"
+//! [1, 2, 3]  (1, "two")  {x: 1, y: 2}  {:}
+//! circle(1.0)  red  some(1)  none  ok  err("bad")  {read, write}
+//! ```
+
+use std::fmt::{self, Write};
+
+use thiserror::Error;
+
+use crate::{Val, ValType};
+
+const KEYWORDS: &[&str] = &["true", "false", "inf", "nan", "some", "none", "ok", "err"];
+
+/// Writes `name` as a WAVE label, escaping it with `%` if it collides with a keyword.
+fn write_label(f: &mut fmt::Formatter<'_>, name: &str) -> fmt::Result {
+    if KEYWORDS.contains(&name) {
+        f.write_char('%')?;
+    }
+    f.write_str(name)
+}
+
+fn write_escaped(f: &mut fmt::Formatter<'_>, c: char, quote: char) -> fmt::Result {
+    match c {
+        '\\' => f.write_str("\\\\"),
+        '\n' => f.write_str("\\n"),
+        '\r' => f.write_str("\\r"),
+        '\t' => f.write_str("\\t"),
+        c if c == quote => write!(f, "\\{c}"),
+        c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32),
+        c => f.write_char(c),
+    }
+}
+
+/// Writes a float, given as `f64` for classification and formatted through `v` so that `f32`
+/// values keep their shortest representation.
+fn write_float(f: &mut fmt::Formatter<'_>, class: f64, v: impl fmt::Display) -> fmt::Result {
+    if class.is_nan() {
+        f.write_str("nan")
+    } else if class.is_infinite() {
+        f.write_str(if class > 0.0 { "inf" } else { "-inf" })
+    } else {
+        write!(f, "{v}")
+    }
+}
+
+fn write_seq<'a>(
+    f: &mut fmt::Formatter<'_>,
+    open: &str,
+    items: impl IntoIterator<Item = &'a Val>,
+    close: &str,
+) -> fmt::Result {
+    f.write_str(open)?;
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    f.write_str(close)
+}
+
+fn write_payload(f: &mut fmt::Formatter<'_>, payload: &Option<Box<Val>>) -> fmt::Result {
+    if let Some(payload) = payload {
+        write!(f, "({payload})")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Val {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Val::Bool(v) => write!(f, "{v}"),
+            Val::S8(v) => write!(f, "{v}"),
+            Val::U8(v) => write!(f, "{v}"),
+            Val::S16(v) => write!(f, "{v}"),
+            Val::U16(v) => write!(f, "{v}"),
+            Val::S32(v) => write!(f, "{v}"),
+            Val::U32(v) => write!(f, "{v}"),
+            Val::S64(v) => write!(f, "{v}"),
+            Val::U64(v) => write!(f, "{v}"),
+            Val::Float32(v) => write_float(f, *v as f64, v),
+            Val::Float64(v) => write_float(f, *v, v),
+            Val::Char(v) => {
+                f.write_char('\'')?;
+                write_escaped(f, *v, '\'')?;
+                f.write_char('\'')
+            }
+            Val::String(v) => {
+                f.write_char('"')?;
+                for c in v.chars() {
+                    write_escaped(f, c, '"')?;
+                }
+                f.write_char('"')
+            }
+            Val::List(items) => write_seq(f, "[", items, "]"),
+            Val::Tuple(items) => write_seq(f, "(", items, ")"),
+            Val::Record(fields) if fields.is_empty() => f.write_str("{:}"),
+            Val::Record(fields) => {
+                f.write_char('{')?;
+                for (i, (name, val)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_label(f, name)?;
+                    write!(f, ": {val}")?;
+                }
+                f.write_char('}')
+            }
+            Val::Variant(name, payload) => {
+                write_label(f, name)?;
+                write_payload(f, payload)
+            }
+            Val::Enum(name) => write_label(f, name),
+            Val::Option(None) => f.write_str("none"),
+            Val::Option(Some(v)) => write!(f, "some({v})"),
+            Val::Result(Ok(v)) => {
+                f.write_str("ok")?;
+                write_payload(f, v)
+            }
+            Val::Result(Err(v)) => {
+                f.write_str("err")?;
+                write_payload(f, v)
+            }
+            Val::Flags(names) => {
+                f.write_char('{')?;
+                for (i, name) in names.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_label(f, name)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// A WAVE document could not be parsed as the expected type.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{message} at offset {offset}")]
+pub struct WaveError {
+    offset: usize,
+    message: String,
+}
+
+impl WaveError {
+    /// The byte offset into the input at which parsing failed.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Val {
+    /// Parses a WAVE-encoded value of type `ty`.
+    pub fn parse_wave(input: &str, ty: &ValType) -> Result<Val, WaveError> {
+        let mut parser = Parser { input, pos: 0 };
+        let val = parser.value(ty)?;
+        parser.skip_ws();
+        if parser.pos < input.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(val)
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+/// A label as written in the input; `escaped` labels never match keywords.
+struct Label<'a> {
+    name: &'a str,
+    escaped: bool,
+}
+
+impl Label<'_> {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        !self.escaped && self.name == keyword
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: impl Into<String>) -> WaveError {
+        WaveError {
+            offset: self.pos,
+            message: message.into(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Consumes `c` if it is the next non-whitespace character.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), WaveError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{c}`")))
+        }
+    }
+
+    /// Consumes a run of characters matching `pred` and returns it.
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn label(&mut self) -> Result<Label<'a>, WaveError> {
+        self.skip_ws();
+        let escaped = self.eat('%');
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '-');
+        if name.is_empty() {
+            return Err(self.error("expected a label"));
+        }
+        Ok(Label { name, escaped })
+    }
+
+    /// Parses `(payload)` if `ty` is present, and nothing otherwise.
+    fn payload(&mut self, ty: Option<&ValType>, case: &str) -> Result<Option<Box<Val>>, WaveError> {
+        match ty {
+            Some(ty) => {
+                self.expect('(')?;
+                let val = self.value(ty)?;
+                self.expect(')')?;
+                Ok(Some(Box::new(val)))
+            }
+            None if self.eat('(') => Err(self.error(format!("case `{case}` has no payload"))),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses comma-separated items up to `close`, allowing a trailing comma.
+    fn items(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<(), WaveError>,
+    ) -> Result<(), WaveError> {
+        loop {
+            if self.eat(close) {
+                return Ok(());
+            }
+            item(self)?;
+            if !self.eat(',') {
+                return self.expect(close);
+            }
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, kind: &str) -> Result<T, WaveError> {
+        self.skip_ws();
+        let start = self.pos;
+        let token = self.take_while(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        // Rust's integer parser accepts a leading `+` that WAVE does not.
+        match token.parse() {
+            Ok(v) if !token.starts_with('+') => Ok(v),
+            _ => Err(WaveError {
+                offset: start,
+                message: format!("invalid {kind} `{token}`"),
+            }),
+        }
+    }
+
+    fn float<T: std::str::FromStr>(&mut self, kind: &str) -> Result<T, WaveError> {
+        self.skip_ws();
+        let start = self.pos;
+        let token = self.take_while(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        // Rust's float parser accepts `inf` and `nan` but also spellings like `NaN` or
+        // `infinity` and a leading `+` that WAVE does not.
+        let valid = matches!(token, "nan" | "inf" | "-inf")
+            || (!token.starts_with('+')
+                && !token
+                    .chars()
+                    .any(|c| c.is_ascii_alphabetic() && c != 'e' && c != 'E'));
+        match token.parse() {
+            Ok(v) if valid => Ok(v),
+            _ => Err(WaveError {
+                offset: start,
+                message: format!("invalid {kind} `{token}`"),
+            }),
+        }
+    }
+
+    /// Parses the contents of a quoted char or string literal, after the opening quote.
+    fn quoted(&mut self, quote: char) -> Result<String, WaveError> {
+        // Errors point at the offending character rather than at the start of the literal.
+        let start = self.pos;
+        let error_at = |i: usize, message: String| WaveError {
+            offset: start + i,
+            message,
+        };
+        let mut out = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                c if c == quote => {
+                    self.pos += i + c.len_utf8();
+                    return Ok(out);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('\\') => '\\',
+                        Some('\'') => '\'',
+                        Some('"') => '"',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let rest = &self.rest()[i + 2..];
+                            let code = rest
+                                .strip_prefix('{')
+                                .and_then(|r| r.split_once('}'))
+                                .map(|(hex, _)| hex)
+                                .ok_or_else(|| error_at(i, "invalid unicode escape".into()))?;
+                            for _ in 0..code.len() + 2 {
+                                chars.next();
+                            }
+                            u32::from_str_radix(code, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| {
+                                    error_at(i, format!("invalid code point `{code}`"))
+                                })?
+                        }
+                        _ => return Err(error_at(i, "invalid escape sequence".into())),
+                    };
+                    out.push(escaped);
+                }
+                '\n' | '\r' => return Err(error_at(i, "unterminated literal".into())),
+                c => out.push(c),
+            }
+        }
+        Err(error_at(self.rest().len(), "unterminated literal".into()))
+    }
+
+    fn value(&mut self, ty: &ValType) -> Result<Val, WaveError> {
+        self.skip_ws();
+        match ty {
+            ValType::Bool => {
+                let label = self.label()?;
+                if label.is_keyword("true") {
+                    Ok(Val::Bool(true))
+                } else if label.is_keyword("false") {
+                    Ok(Val::Bool(false))
+                } else {
+                    Err(self.error("expected `true` or `false`"))
+                }
+            }
+            ValType::S8 => self.number("s8").map(Val::S8),
+            ValType::U8 => self.number("u8").map(Val::U8),
+            ValType::S16 => self.number("s16").map(Val::S16),
+            ValType::U16 => self.number("u16").map(Val::U16),
+            ValType::S32 => self.number("s32").map(Val::S32),
+            ValType::U32 => self.number("u32").map(Val::U32),
+            ValType::S64 => self.number("s64").map(Val::S64),
+            ValType::U64 => self.number("u64").map(Val::U64),
+            ValType::Float32 => self.float("f32").map(Val::Float32),
+            ValType::Float64 => self.float("f64").map(Val::Float64),
+            ValType::Char => {
+                self.expect('\'')?;
+                let s = self.quoted('\'')?;
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(Val::Char(c)),
+                    _ => Err(self.error("char literals must contain exactly one character")),
+                }
+            }
+            ValType::String => {
+                self.expect('"')?;
+                self.quoted('"').map(Val::String)
+            }
+            ValType::List(ty) => {
+                self.expect('[')?;
+                let mut items = vec![];
+                self.items(']', |p| {
+                    items.push(p.value(ty)?);
+                    Ok(())
+                })?;
+                Ok(Val::List(items))
+            }
+            ValType::Tuple(tys) => {
+                self.expect('(')?;
+                let mut items = vec![];
+                self.items(')', |p| {
+                    let ty = tys
+                        .get(items.len())
+                        .ok_or_else(|| p.error(format!("expected {} tuple elements", tys.len())))?;
+                    items.push(p.value(ty)?);
+                    Ok(())
+                })?;
+                if items.len() != tys.len() {
+                    return Err(self.error(format!("expected {} tuple elements", tys.len())));
+                }
+                Ok(Val::Tuple(items))
+            }
+            ValType::Record(field_tys) => {
+                self.expect('{')?;
+                self.skip_ws();
+                // `{}` is an empty set of flags; a record without fields is written `{:}`.
+                if self.peek() == Some('}') {
+                    return Err(self.error("expected a field or `:`"));
+                }
+                let mut fields: Vec<Option<Val>> = vec![None; field_tys.len()];
+                if !self.eat(':') {
+                    self.items('}', |p| {
+                        let label = p.label()?;
+                        let idx = field_tys
+                            .iter()
+                            .position(|(name, _)| name == label.name)
+                            .ok_or_else(|| p.error(format!("unknown field `{}`", label.name)))?;
+                        if fields[idx].is_some() {
+                            return Err(p.error(format!("duplicate field `{}`", label.name)));
+                        }
+                        p.expect(':')?;
+                        fields[idx] = Some(p.value(&field_tys[idx].1)?);
+                        Ok(())
+                    })?;
+                } else {
+                    self.expect('}')?;
+                }
+                field_tys
+                    .iter()
+                    .zip(fields)
+                    .map(|((name, ty), val)| match (val, ty) {
+                        (Some(val), _) => Ok((name.clone(), val)),
+                        (None, ValType::Option(_)) => Ok((name.clone(), Val::Option(None))),
+                        (None, _) => Err(self.error(format!("missing field `{name}`"))),
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Val::Record)
+            }
+            ValType::Variant(cases) => {
+                let label = self.label()?;
+                let (name, ty) = cases
+                    .iter()
+                    .find(|(name, _)| name == label.name)
+                    .ok_or_else(|| self.error(format!("unknown case `{}`", label.name)))?;
+                let payload = self.payload(ty.as_ref(), name)?;
+                Ok(Val::Variant(name.clone(), payload))
+            }
+            ValType::Enum(cases) => {
+                let label = self.label()?;
+                match cases.iter().find(|name| *name == label.name) {
+                    Some(name) => Ok(Val::Enum(name.clone())),
+                    None => Err(self.error(format!("unknown case `{}`", label.name))),
+                }
+            }
+            ValType::Option(ty) => {
+                let label = self.label()?;
+                if label.is_keyword("none") {
+                    Ok(Val::Option(None))
+                } else if label.is_keyword("some") {
+                    self.payload(Some(ty), "some").map(Val::Option)
+                } else {
+                    Err(self.error("expected `some` or `none`"))
+                }
+            }
+            ValType::Result { ok, err } => {
+                let label = self.label()?;
+                if label.is_keyword("ok") {
+                    self.payload(ok.as_deref(), "ok")
+                        .map(|v| Val::Result(Ok(v)))
+                } else if label.is_keyword("err") {
+                    self.payload(err.as_deref(), "err")
+                        .map(|v| Val::Result(Err(v)))
+                } else {
+                    Err(self.error("expected `ok` or `err`"))
+                }
+            }
+            ValType::Flags(flags) => {
+                self.expect('{')?;
+                let mut names: Vec<String> = vec![];
+                self.items('}', |p| {
+                    let label = p.label()?;
+                    match flags.iter().find(|flag| *flag == label.name) {
+                        Some(flag) if !names.contains(flag) => {
+                            names.push(flag.clone());
+                            Ok(())
+                        }
+                        Some(flag) => Err(p.error(format!("duplicate flag `{flag}`"))),
+                        None => Err(p.error(format!("unknown flag `{}`", label.name))),
+                    }
+                })?;
+                Ok(Val::Flags(names))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str, ty: &ValType) -> Result<Val, WaveError> {
+        Val::parse_wave(input, ty)
+    }
+
+    /// `Val` has no `PartialEq` because of its floats, so values are compared by their debug
+    /// representation.
+    fn assert_parses(input: &str, ty: &ValType, expected: Val) {
+        match parse(input, ty) {
+            Ok(val) => assert_eq!(format!("{val:?}"), format!("{expected:?}"), "{input}"),
+            Err(err) => panic!("`{input}` did not parse: {err}"),
+        }
+    }
+
+    fn point() -> ValType {
+        ValType::Record(vec![
+            ("x".to_string(), ValType::S32),
+            ("y".to_string(), ValType::Option(Box::new(ValType::S32))),
+        ])
+    }
+
+    #[test]
+    fn round_trips() {
+        let cases = [
+            (Val::S32(-42), ValType::S32),
+            (Val::Float64(f64::NEG_INFINITY), ValType::Float64),
+            (Val::Char('\''), ValType::Char),
+            (Val::String("a \"b\"\n\u{7}".into()), ValType::String),
+            (
+                Val::List(vec![Val::U8(1), Val::U8(2)]),
+                ValType::List(Box::new(ValType::U8)),
+            ),
+            (
+                Val::Record(vec![
+                    ("x".into(), Val::S32(1)),
+                    ("y".into(), Val::Option(Some(Box::new(Val::S32(2))))),
+                ]),
+                point(),
+            ),
+            (Val::Record(vec![]), ValType::Record(vec![])),
+            (
+                Val::Variant("none".into(), None),
+                ValType::Variant(vec![("none".into(), None)]),
+            ),
+            (
+                Val::Result(Err(Some(Box::new(Val::String("bad".into()))))),
+                ValType::Result {
+                    ok: None,
+                    err: Some(Box::new(ValType::String)),
+                },
+            ),
+            (
+                Val::Flags(vec![]),
+                ValType::Flags(vec!["read".into(), "write".into()]),
+            ),
+        ];
+        for (val, ty) in cases {
+            assert_parses(&val.to_string(), &ty, val);
+        }
+    }
+
+    #[test]
+    fn numbers_reject_a_leading_plus() {
+        assert_parses("-1", &ValType::S8, Val::S8(-1));
+        assert!(parse("+1", &ValType::S8).is_err());
+        assert!(parse("+1", &ValType::U32).is_err());
+        assert!(parse("+1.5", &ValType::Float32).is_err());
+        assert!(parse("+inf", &ValType::Float64).is_err());
+        assert_parses("1e+2", &ValType::Float64, Val::Float64(100.0));
+    }
+
+    #[test]
+    fn floats_reject_other_spellings() {
+        assert!(parse("NaN", &ValType::Float64).is_err());
+        assert!(parse("infinity", &ValType::Float64).is_err());
+        assert!(matches!(parse("nan", &ValType::Float64), Ok(Val::Float64(v)) if v.is_nan()));
+    }
+
+    #[test]
+    fn empty_records_need_a_colon() {
+        let err = parse("{}", &ValType::Record(vec![])).unwrap_err();
+        assert_eq!(err.offset(), 1);
+        assert!(parse("{}", &point()).is_err());
+        assert_parses(
+            "{x: 1}",
+            &point(),
+            Val::Record(vec![
+                ("x".into(), Val::S32(1)),
+                ("y".into(), Val::Option(None)),
+            ]),
+        );
+        assert_parses(
+            "{}",
+            &ValType::Flags(vec!["read".into()]),
+            Val::Flags(vec![]),
+        );
+    }
+
+    #[test]
+    fn labels_escape_keywords() {
+        let ty = ValType::Enum(vec!["none".into(), "red".into()]);
+        assert_eq!(Val::Enum("none".into()).to_string(), "%none");
+        assert_parses("%none", &ty, Val::Enum("none".into()));
+        assert_parses("red", &ty, Val::Enum("red".into()));
+    }
+
+    #[test]
+    fn literal_errors_point_at_the_offending_character() {
+        let err = parse(r#""abc\qdef""#, &ValType::String).unwrap_err();
+        assert_eq!(err.offset(), 4);
+        let err = parse(r#"  "a\u{110000}""#, &ValType::String).unwrap_err();
+        assert_eq!(err.offset(), 4);
+        let err = parse("\"ab\ncd\"", &ValType::String).unwrap_err();
+        assert_eq!(err.offset(), 3);
+        let err = parse("\"abc", &ValType::String).unwrap_err();
+        assert_eq!(err.offset(), 4);
+    }
+
+    #[test]
+    fn rejects_trailing_input() {
+        let err = parse("1 2", &ValType::U8).unwrap_err();
+        assert_eq!(err.offset(), 2);
+    }
+}
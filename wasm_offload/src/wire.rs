@@ -0,0 +1,549 @@
+//! A compact, self-describing binary encoding for [`Val`].
+//!
+//! An encoded value starts with the magic bytes `WOV` and a version byte, followed by the value
+//! itself. Every value is a tag byte followed by its payload: integers are LEB128 (signed ones
+//! zigzag-encoded), floats are little-endian IEEE 754, and strings, lists and other sequences
+//! are prefixed with their length.
+
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use crate::Val;
+
+const MAGIC: &[u8; 3] = b"WOV";
+const VERSION: u8 = 1;
+
+const TAG_BOOL: u8 = 0;
+const TAG_S8: u8 = 1;
+const TAG_U8: u8 = 2;
+const TAG_S16: u8 = 3;
+const TAG_U16: u8 = 4;
+const TAG_S32: u8 = 5;
+const TAG_U32: u8 = 6;
+const TAG_S64: u8 = 7;
+const TAG_U64: u8 = 8;
+const TAG_F32: u8 = 9;
+const TAG_F64: u8 = 10;
+const TAG_CHAR: u8 = 11;
+const TAG_STRING: u8 = 12;
+const TAG_LIST: u8 = 13;
+const TAG_RECORD: u8 = 14;
+const TAG_TUPLE: u8 = 15;
+const TAG_VARIANT: u8 = 16;
+const TAG_ENUM: u8 = 17;
+const TAG_OPTION: u8 = 18;
+const TAG_RESULT: u8 = 19;
+const TAG_FLAGS: u8 = 20;
+
+/// Upper bound on elements preallocated for a sequence before any of them have been read.
+const MAX_PREALLOC: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum WireError {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("input is not an encoded value")]
+    BadMagic,
+    #[error("unsupported encoding version {0}")]
+    UnsupportedVersion(u8),
+    #[error("invalid tag {0}")]
+    InvalidTag(u8),
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+    #[error("value is nested deeper than {0} levels")]
+    TooDeep(usize),
+    #[error("value needs more than the allowed {0} bytes")]
+    TooLarge(usize),
+}
+
+/// Bounds on the resources [`Val::decode_with_limits`] may use for one value.
+#[derive(Clone, Debug)]
+pub struct DecodeLimits {
+    /// Maximum nesting of lists, records, tuples, variants, options and results.
+    pub max_depth: usize,
+    /// Maximum number of bytes allocated for the decoded value, counting both string
+    /// contents and the in-memory size of every `Val`.
+    pub max_bytes: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 128,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Val {
+    /// Writes this value in the binary wire encoding.
+    pub fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        encode_val(self, w)
+    }
+
+    /// Reads a value written by [`Val::encode`] with the default [`DecodeLimits`].
+    pub fn decode(r: &mut impl Read) -> Result<Val, WireError> {
+        Self::decode_with_limits(r, &DecodeLimits::default())
+    }
+
+    /// Reads a value written by [`Val::encode`], failing once `limits` are exceeded.
+    pub fn decode_with_limits(r: &mut impl Read, limits: &DecodeLimits) -> Result<Val, WireError> {
+        let mut header = [0; 4];
+        r.read_exact(&mut header)?;
+        if &header[..3] != MAGIC {
+            return Err(WireError::BadMagic);
+        }
+        if header[3] != VERSION {
+            return Err(WireError::UnsupportedVersion(header[3]));
+        }
+        let mut decoder = Decoder {
+            r,
+            max_depth: limits.max_depth,
+            max_bytes: limits.max_bytes,
+            budget: limits.max_bytes,
+        };
+        decoder.val(0)
+    }
+}
+
+fn write_uleb(w: &mut impl Write, mut v: u64) -> io::Result<()> {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            return w.write_all(&[byte]);
+        }
+        w.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn write_sleb(w: &mut impl Write, v: i64) -> io::Result<()> {
+    write_uleb(w, ((v << 1) ^ (v >> 63)) as u64)
+}
+
+fn write_str(w: &mut impl Write, s: &str) -> io::Result<()> {
+    write_uleb(w, s.len() as u64)?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_payload(w: &mut impl Write, payload: &Option<Box<Val>>) -> io::Result<()> {
+    match payload {
+        Some(val) => {
+            w.write_all(&[1])?;
+            encode_val(val, w)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+fn encode_val(val: &Val, w: &mut impl Write) -> io::Result<()> {
+    match val {
+        Val::Bool(v) => w.write_all(&[TAG_BOOL, *v as u8]),
+        Val::S8(v) => w.write_all(&[TAG_S8, *v as u8]),
+        Val::U8(v) => w.write_all(&[TAG_U8, *v]),
+        Val::S16(v) => {
+            w.write_all(&[TAG_S16])?;
+            write_sleb(w, *v as i64)
+        }
+        Val::U16(v) => {
+            w.write_all(&[TAG_U16])?;
+            write_uleb(w, *v as u64)
+        }
+        Val::S32(v) => {
+            w.write_all(&[TAG_S32])?;
+            write_sleb(w, *v as i64)
+        }
+        Val::U32(v) => {
+            w.write_all(&[TAG_U32])?;
+            write_uleb(w, *v as u64)
+        }
+        Val::S64(v) => {
+            w.write_all(&[TAG_S64])?;
+            write_sleb(w, *v)
+        }
+        Val::U64(v) => {
+            w.write_all(&[TAG_U64])?;
+            write_uleb(w, *v)
+        }
+        Val::Float32(v) => {
+            w.write_all(&[TAG_F32])?;
+            w.write_all(&v.to_le_bytes())
+        }
+        Val::Float64(v) => {
+            w.write_all(&[TAG_F64])?;
+            w.write_all(&v.to_le_bytes())
+        }
+        Val::Char(v) => {
+            w.write_all(&[TAG_CHAR])?;
+            write_uleb(w, *v as u64)
+        }
+        Val::String(v) => {
+            w.write_all(&[TAG_STRING])?;
+            write_str(w, v)
+        }
+        Val::List(items) | Val::Tuple(items) => {
+            let tag = if matches!(val, Val::List(_)) {
+                TAG_LIST
+            } else {
+                TAG_TUPLE
+            };
+            w.write_all(&[tag])?;
+            write_uleb(w, items.len() as u64)?;
+            items.iter().try_for_each(|item| encode_val(item, w))
+        }
+        Val::Record(fields) => {
+            w.write_all(&[TAG_RECORD])?;
+            write_uleb(w, fields.len() as u64)?;
+            fields.iter().try_for_each(|(name, val)| {
+                write_str(w, name)?;
+                encode_val(val, w)
+            })
+        }
+        Val::Variant(case, payload) => {
+            w.write_all(&[TAG_VARIANT])?;
+            write_str(w, case)?;
+            write_payload(w, payload)
+        }
+        Val::Enum(case) => {
+            w.write_all(&[TAG_ENUM])?;
+            write_str(w, case)
+        }
+        Val::Option(payload) => {
+            w.write_all(&[TAG_OPTION])?;
+            write_payload(w, payload)
+        }
+        Val::Result(res) => {
+            let (is_err, payload) = match res {
+                Ok(payload) => (0, payload),
+                Err(payload) => (1, payload),
+            };
+            w.write_all(&[TAG_RESULT, is_err])?;
+            write_payload(w, payload)
+        }
+        Val::Flags(names) => {
+            w.write_all(&[TAG_FLAGS])?;
+            write_uleb(w, names.len() as u64)?;
+            names.iter().try_for_each(|name| write_str(w, name))
+        }
+    }
+}
+
+struct Decoder<'a, R> {
+    r: &'a mut R,
+    max_depth: usize,
+    max_bytes: usize,
+    budget: usize,
+}
+
+impl<R: Read> Decoder<'_, R> {
+    /// Accounts for `bytes` more bytes of allocation.
+    fn charge(&mut self, bytes: usize) -> Result<(), WireError> {
+        self.budget = self
+            .budget
+            .checked_sub(bytes)
+            .ok_or(WireError::TooLarge(self.max_bytes))?;
+        Ok(())
+    }
+
+    fn byte(&mut self) -> Result<u8, WireError> {
+        let mut buf = [0];
+        self.r.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], WireError> {
+        let mut buf = [0; N];
+        self.r.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn uleb(&mut self) -> Result<u64, WireError> {
+        let mut result = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if shift == 63 && bits > 1 {
+                return Err(WireError::Invalid("LEB128 integer"));
+            }
+            result |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(WireError::Invalid("LEB128 integer"))
+    }
+
+    fn sleb(&mut self) -> Result<i64, WireError> {
+        let v = self.uleb()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn int<T: TryFrom<u64>>(&mut self, what: &'static str) -> Result<T, WireError> {
+        T::try_from(self.uleb()?).map_err(|_| WireError::Invalid(what))
+    }
+
+    fn sint<T: TryFrom<i64>>(&mut self, what: &'static str) -> Result<T, WireError> {
+        T::try_from(self.sleb()?).map_err(|_| WireError::Invalid(what))
+    }
+
+    fn len(&mut self) -> Result<usize, WireError> {
+        self.int("length")
+    }
+
+    fn string(&mut self) -> Result<String, WireError> {
+        let len = self.len()?;
+        self.charge(len)?;
+        let mut buf = Vec::with_capacity(len.min(MAX_PREALLOC));
+        let read = (&mut *self.r).take(len as u64).read_to_end(&mut buf)?;
+        if read != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        String::from_utf8(buf).map_err(|_| WireError::Invalid("UTF-8 string"))
+    }
+
+    fn payload(&mut self, depth: usize) -> Result<Option<Box<Val>>, WireError> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => {
+                self.charge(std::mem::size_of::<Val>())?;
+                Ok(Some(Box::new(self.val(depth + 1)?)))
+            }
+            _ => Err(WireError::Invalid("payload marker")),
+        }
+    }
+
+    /// Reads `len` items with `item`, never preallocating more than `MAX_PREALLOC` of them.
+    ///
+    /// All items are charged up front, so that a length the budget can't hold fails before
+    /// anything is allocated.
+    fn seq<T>(
+        &mut self,
+        len: usize,
+        mut item: impl FnMut(&mut Self) -> Result<T, WireError>,
+    ) -> Result<Vec<T>, WireError> {
+        let bytes = len
+            .checked_mul(std::mem::size_of::<T>())
+            .ok_or(WireError::TooLarge(self.max_bytes))?;
+        self.charge(bytes)?;
+        let mut items = Vec::with_capacity(len.min(MAX_PREALLOC));
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn val(&mut self, depth: usize) -> Result<Val, WireError> {
+        if depth > self.max_depth {
+            return Err(WireError::TooDeep(self.max_depth));
+        }
+        Ok(match self.byte()? {
+            TAG_BOOL => match self.byte()? {
+                0 => Val::Bool(false),
+                1 => Val::Bool(true),
+                _ => return Err(WireError::Invalid("bool")),
+            },
+            TAG_S8 => Val::S8(self.byte()? as i8),
+            TAG_U8 => Val::U8(self.byte()?),
+            TAG_S16 => Val::S16(self.sint("s16")?),
+            TAG_U16 => Val::U16(self.int("u16")?),
+            TAG_S32 => Val::S32(self.sint("s32")?),
+            TAG_U32 => Val::U32(self.int("u32")?),
+            TAG_S64 => Val::S64(self.sleb()?),
+            TAG_U64 => Val::U64(self.uleb()?),
+            TAG_F32 => Val::Float32(f32::from_le_bytes(self.array()?)),
+            TAG_F64 => Val::Float64(f64::from_le_bytes(self.array()?)),
+            TAG_CHAR => {
+                let c = self.int("char")?;
+                Val::Char(char::from_u32(c).ok_or(WireError::Invalid("char"))?)
+            }
+            TAG_STRING => Val::String(self.string()?),
+            TAG_LIST => {
+                let len = self.len()?;
+                Val::List(self.seq(len, |d| d.val(depth + 1))?)
+            }
+            TAG_TUPLE => {
+                let len = self.len()?;
+                Val::Tuple(self.seq(len, |d| d.val(depth + 1))?)
+            }
+            TAG_RECORD => {
+                let len = self.len()?;
+                Val::Record(self.seq(len, |d| Ok((d.string()?, d.val(depth + 1)?)))?)
+            }
+            TAG_VARIANT => {
+                let case = self.string()?;
+                Val::Variant(case, self.payload(depth)?)
+            }
+            TAG_ENUM => Val::Enum(self.string()?),
+            TAG_OPTION => Val::Option(self.payload(depth)?),
+            TAG_RESULT => match self.byte()? {
+                0 => Val::Result(Ok(self.payload(depth)?)),
+                1 => Val::Result(Err(self.payload(depth)?)),
+                _ => return Err(WireError::Invalid("result marker")),
+            },
+            TAG_FLAGS => {
+                let len = self.len()?;
+                Val::Flags(self.seq(len, |d| d.string())?)
+            }
+            tag => return Err(WireError::InvalidTag(tag)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(val: &Val) -> Vec<u8> {
+        let mut buf = vec![];
+        val.encode(&mut buf).unwrap();
+        buf
+    }
+
+    fn decode(bytes: &[u8], limits: &DecodeLimits) -> Result<Val, WireError> {
+        Val::decode_with_limits(&mut &bytes[..], limits)
+    }
+
+    /// A header followed by `body`.
+    fn encoded(body: &[u8]) -> Vec<u8> {
+        [&MAGIC[..], &[VERSION], body].concat()
+    }
+
+    #[test]
+    fn round_trips() {
+        let val = Val::Record(vec![
+            ("flag".into(), Val::Bool(true)),
+            ("small".into(), Val::S8(-128)),
+            ("wide".into(), Val::S64(i64::MIN)),
+            ("big".into(), Val::U64(u64::MAX)),
+            ("half".into(), Val::Float32(-0.5)),
+            ("nan".into(), Val::Float64(f64::NAN)),
+            ("char".into(), Val::Char('ß')),
+            ("text".into(), Val::String("héllo".into())),
+            (
+                "list".into(),
+                Val::List(vec![Val::U16(0), Val::U16(u16::MAX)]),
+            ),
+            ("pair".into(), Val::Tuple(vec![Val::S32(-1), Val::U32(1)])),
+            (
+                "shape".into(),
+                Val::Variant("circle".into(), Some(Box::new(Val::Float64(1.0)))),
+            ),
+            ("color".into(), Val::Enum("red".into())),
+            ("none".into(), Val::Option(None)),
+            ("ok".into(), Val::Result(Ok(None))),
+            (
+                "err".into(),
+                Val::Result(Err(Some(Box::new(Val::String("bad".into()))))),
+            ),
+            ("flags".into(), Val::Flags(vec!["read".into()])),
+        ]);
+        let decoded = Val::decode(&mut &encode(&val)[..]).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{val:?}"));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(
+            decode(b"WOX\x01", &DecodeLimits::default()),
+            Err(WireError::BadMagic)
+        ));
+        assert!(matches!(
+            decode(b"WOV\x02", &DecodeLimits::default()),
+            Err(WireError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn truncated_input_keeps_the_io_error() {
+        let mut bytes = encode(&Val::String("hello".into()));
+        bytes.pop();
+        let err = decode(&bytes, &DecodeLimits::default()).unwrap_err();
+        assert!(matches!(&err, WireError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+        assert!(err.to_string().starts_with("i/o error: "));
+    }
+
+    #[test]
+    fn enforces_the_depth_limit() {
+        let mut val = Val::Option(None);
+        for _ in 0..4 {
+            val = Val::List(vec![val]);
+        }
+        let bytes = encode(&val);
+        let limits = DecodeLimits {
+            max_depth: 3,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            decode(&bytes, &limits),
+            Err(WireError::TooDeep(3))
+        ));
+        let limits = DecodeLimits {
+            max_depth: 4,
+            ..DecodeLimits::default()
+        };
+        assert!(decode(&bytes, &limits).is_ok());
+    }
+
+    #[test]
+    fn huge_lengths_fail_before_allocating() {
+        let limits = DecodeLimits {
+            max_depth: 8,
+            max_bytes: 1024,
+        };
+        // A list claiming `u64::MAX` elements, and a string claiming as many bytes, followed by
+        // nothing.
+        let huge = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        for tag in [TAG_LIST, TAG_STRING] {
+            let bytes = encoded(&[&[tag][..], &huge].concat());
+            assert!(matches!(
+                decode(&bytes, &limits),
+                Err(WireError::TooLarge(1024))
+            ));
+        }
+        // Within `usize` but over the budget.
+        let bytes = encoded(&[TAG_LIST, 0x80, 0x08]);
+        assert!(matches!(
+            decode(&bytes, &limits),
+            Err(WireError::TooLarge(1024))
+        ));
+    }
+
+    #[test]
+    fn enforces_the_byte_limit() {
+        let val = Val::String("x".repeat(100));
+        let bytes = encode(&val);
+        let limits = |max_bytes| DecodeLimits {
+            max_depth: 8,
+            max_bytes,
+        };
+        assert!(matches!(
+            decode(&bytes, &limits(99)),
+            Err(WireError::TooLarge(99))
+        ));
+        assert!(decode(&bytes, &limits(100)).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let limits = DecodeLimits::default();
+        assert!(matches!(
+            decode(&encoded(&[TAG_BOOL, 2]), &limits),
+            Err(WireError::Invalid("bool"))
+        ));
+        assert!(matches!(
+            decode(&encoded(&[TAG_CHAR, 0x80, 0xb0, 0x03]), &limits),
+            Err(WireError::Invalid("char"))
+        ));
+        assert!(matches!(
+            decode(&encoded(&[TAG_STRING, 1, 0xff]), &limits),
+            Err(WireError::Invalid("UTF-8 string"))
+        ));
+        assert!(matches!(
+            decode(&encoded(&[TAG_FLAGS + 1]), &limits),
+            Err(WireError::InvalidTag(21))
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+use wasm_offload::{FromVal, HasValType, IntoVal, Val, ValType};
+
+#[derive(Clone, Debug, PartialEq, IntoVal, FromVal, HasValType)]
+struct Point {
+    x: i32,
+    y_offset: Option<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, IntoVal, FromVal, HasValType)]
+struct Pair(u8, String);
+
+#[derive(Clone, Debug, PartialEq, IntoVal, FromVal, HasValType)]
+struct Marker;
+
+#[derive(Clone, Debug, PartialEq, IntoVal, FromVal, HasValType)]
+enum Color {
+    Red,
+    DarkBlue,
+}
+
+#[derive(Clone, Debug, PartialEq, IntoVal, FromVal, HasValType)]
+enum Shape {
+    Circle(f32),
+    Rect { width: u32, height: u32 },
+    Empty,
+}
+
+/// Converts `value` to a `Val`, compares it with `expected`, checks it against the derived
+/// `ValType` and converts it back.
+///
+/// `Val` has no `PartialEq` because of its floats, so values are compared by their debug
+/// representation.
+fn assert_round_trips<T>(value: T, expected: Val)
+where
+    T: Clone + std::fmt::Debug + PartialEq + Into<Val> + FromVal + HasValType,
+{
+    let val: Val = value.clone().into();
+    assert_eq!(format!("{val:?}"), format!("{expected:?}"));
+    val.type_check(&T::val_type()).unwrap();
+    assert_eq!(T::from_val(val).unwrap(), value);
+}
+
+#[test]
+fn structs_round_trip() {
+    assert_round_trips(
+        Point {
+            x: -1,
+            y_offset: Some(2),
+        },
+        Val::Record(vec![
+            ("x".to_string(), Val::S32(-1)),
+            (
+                "y-offset".to_string(),
+                Val::Option(Some(Box::new(Val::U8(2)))),
+            ),
+        ]),
+    );
+    assert_round_trips(
+        Pair(1, "one".to_string()),
+        Val::Tuple(vec![Val::U8(1), Val::String("one".to_string())]),
+    );
+    assert_round_trips(Marker, Val::Tuple(vec![]));
+}
+
+#[test]
+fn enums_round_trip() {
+    assert_round_trips(Color::DarkBlue, Val::Enum("dark-blue".to_string()));
+    assert_round_trips(
+        Shape::Circle(1.5),
+        Val::Variant("circle".to_string(), Some(Box::new(Val::Float32(1.5)))),
+    );
+    assert_round_trips(
+        Shape::Rect {
+            width: 2,
+            height: 3,
+        },
+        Val::Variant(
+            "rect".to_string(),
+            Some(Box::new(Val::Record(vec![
+                ("width".to_string(), Val::U32(2)),
+                ("height".to_string(), Val::U32(3)),
+            ]))),
+        ),
+    );
+    assert_round_trips(Shape::Empty, Val::Variant("empty".to_string(), None));
+}
+
+#[test]
+fn val_types_follow_the_wit_names() {
+    assert_eq!(
+        Point::val_type(),
+        ValType::Record(vec![
+            ("x".to_string(), ValType::S32),
+            (
+                "y-offset".to_string(),
+                ValType::Option(Box::new(ValType::U8))
+            ),
+        ])
+    );
+    assert_eq!(
+        Pair::val_type(),
+        ValType::Tuple(vec![ValType::U8, ValType::String])
+    );
+    assert_eq!(Marker::val_type(), ValType::Tuple(vec![]));
+    assert_eq!(
+        Color::val_type(),
+        ValType::Enum(vec!["red".to_string(), "dark-blue".to_string()])
+    );
+    assert_eq!(
+        Shape::val_type(),
+        ValType::Variant(vec![
+            ("circle".to_string(), Some(ValType::Float32)),
+            (
+                "rect".to_string(),
+                Some(ValType::Record(vec![
+                    ("width".to_string(), ValType::U32),
+                    ("height".to_string(), ValType::U32),
+                ]))
+            ),
+            ("empty".to_string(), None),
+        ])
+    );
+}
+
+#[test]
+fn mismatched_fields_are_reported_with_their_path() {
+    let val = Val::Record(vec![
+        ("x".to_string(), Val::String("one".to_string())),
+        ("y-offset".to_string(), Val::Option(None)),
+    ]);
+    let err = Point::from_val(val).unwrap_err();
+    assert_eq!(err.to_string(), "expected s32, found string at `x`");
+
+    let val = Val::Variant(
+        "rect".to_string(),
+        Some(Box::new(Val::Record(vec![
+            ("width".to_string(), Val::U32(2)),
+            ("height".to_string(), Val::S32(3)),
+        ]))),
+    );
+    let err = Shape::from_val(val).unwrap_err();
+    assert_eq!(err.path().to_string(), "<rect>.height");
+    assert_eq!((err.expected(), err.found()), ("u32", "s32"));
+
+    let err = Color::from_val(Val::Enum("green".to_string())).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "expected one of red, dark-blue, found case `green`"
+    );
+}
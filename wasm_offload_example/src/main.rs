@@ -1,6 +1,6 @@
 mod types;
 use types::Point;
-use wasm_offload::{offload, OffloadTarget};
+use wasm_offload::offload;
 
 wasm_offload_wasmtime::init_offload!();
 
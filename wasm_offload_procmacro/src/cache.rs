@@ -0,0 +1,140 @@
+use std::{
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::guest::write_if_changed;
+
+/// Environment variable that overrides the directory built components are cached in.
+const CACHE_DIR_VAR: &str = "WASM_OFFLOAD_CACHE_DIR";
+
+/// Environment variable that, if set, moves the cache to the user's cache directory, where it is
+/// shared by the packages of all workspaces.
+const GLOBAL_CACHE_VAR: &str = "WASM_OFFLOAD_GLOBAL_CACHE";
+
+/// The inputs a guest component is built from, each identified by the hash of its contents.
+#[derive(Default)]
+pub(crate) struct BuildInputs(Vec<(&'static str, String)>);
+
+impl BuildInputs {
+    pub(crate) fn add(&mut self, name: &'static str, contents: impl AsRef<[u8]>) {
+        self.0.push((name, hex(&Sha256::digest(contents))));
+    }
+
+    /// The cache key of the component built from these inputs.
+    pub(crate) fn key(&self) -> String {
+        let mut hasher = Sha256::new();
+        for (name, hash) in &self.0 {
+            hasher.update(name);
+            hasher.update(hash);
+        }
+        hex(&hasher.finalize())
+    }
+
+    /// One line per input, as stored next to the guest crate after a build.
+    pub(crate) fn encode(&self) -> String {
+        self.0
+            .iter()
+            .map(|(name, hash)| format!("{hash} {name}\n"))
+            .collect()
+    }
+
+    /// Explains why the component for these inputs has to be built, given the
+    /// [encoded](Self::encode) inputs of the previous build of the same guest crate.
+    pub(crate) fn rebuild_reason(&self, previous: Option<&str>) -> String {
+        let Some(previous) = previous else {
+            return "it has not been built before".to_string();
+        };
+        let changed: Vec<_> = self
+            .0
+            .iter()
+            .filter(|(name, hash)| {
+                !previous
+                    .lines()
+                    .any(|line| line.split_once(' ') == Some((hash, name)))
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        if changed.is_empty() {
+            "it is not in the build cache".to_string()
+        } else {
+            format!("its {} changed", changed.join(", "))
+        }
+    }
+}
+
+/// Components built by earlier expansions, stored as `<key>.wasm`.
+///
+/// The cache lives in `$WASM_OFFLOAD_CACHE_DIR` or in `default_dir`, which is under the target
+/// dir, so that `cargo clean` removes it. With `$WASM_OFFLOAD_GLOBAL_CACHE` set, it lives in
+/// `wasm-offload/` under the user's cache directory instead, or nowhere if there is none.
+pub(crate) struct BuildCache {
+    dir: Option<PathBuf>,
+}
+
+impl BuildCache {
+    pub(crate) fn open(default_dir: &Path) -> Self {
+        let dir = match env::var_os(CACHE_DIR_VAR) {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None if env::var_os(GLOBAL_CACHE_VAR).is_some() => env::var_os("XDG_CACHE_HOME")
+                .map(|dir| PathBuf::from(dir).join("wasm-offload"))
+                .or_else(|| {
+                    env::var_os("HOME").map(|dir| PathBuf::from(dir).join(".cache/wasm-offload"))
+                }),
+            None => Some(default_dir.to_path_buf()),
+        };
+        Self { dir }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.dir.as_ref()?.join(format!("{key}.wasm"))).ok()
+    }
+
+    pub(crate) fn insert(&self, key: &str, wasm: &[u8]) {
+        // The cache only saves builds, so a component that can't be stored is still usable.
+        if let Some(dir) = &self.dir {
+            let _ = write_if_changed(&dir.join(format!("{key}.wasm")), wasm);
+        }
+    }
+}
+
+/// The output of `rustc -vV`, which identifies the toolchain guests are built with.
+///
+/// The compiler is looked up like cargo does when it builds a guest crate in `dir`: `$RUSTC`
+/// if it is set, and otherwise `rustc`, which rustup resolves for `dir`. The guest crates of a
+/// compilation all share a parent directory, so the toolchain is only looked up once.
+pub(crate) fn toolchain(dir: &Path) -> Result<&'static str, String> {
+    static TOOLCHAIN: OnceLock<Result<String, String>> = OnceLock::new();
+    TOOLCHAIN
+        .get_or_init(|| {
+            let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+            let output = Command::new(&rustc)
+                .current_dir(dir)
+                .arg("-vV")
+                .output()
+                .map_err(|err| format!("could not run `{} -vV`: {err}", rustc.to_string_lossy()))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "`{} -vV` failed: {}",
+                    rustc.to_string_lossy(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+        .as_deref()
+        .map_err(Clone::clone)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{byte:02x}");
+        out
+    })
+}
@@ -0,0 +1,315 @@
+use heck::ToKebabCase;
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_quote, Data, DeriveInput, Fields, Generics, Ident};
+
+/// Adds `bound` to every type parameter of `generics`.
+fn add_bounds(generics: &Generics, bound: syn::TypeParamBound) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(bound.clone());
+    }
+    generics
+}
+
+/// The WIT name of a Rust field or case identifier.
+fn wit_name(ident: &Ident) -> String {
+    ident.to_string().trim_start_matches("r#").to_kebab_case()
+}
+
+/// Builds a `Val` out of the bindings `f0, f1, ...` or the named fields of `fields`.
+fn fields_into_val(fields: &Fields) -> TokenStream {
+    match fields {
+        Fields::Named(named) => {
+            let entries = named.named.iter().map(|f| {
+                let ident = f.ident.as_ref().unwrap();
+                let name = wit_name(ident);
+                quote! {
+                    (#name.to_string(), ::core::convert::Into::<wasm_offload::Val>::into(#ident))
+                }
+            });
+            quote! { wasm_offload::Val::Record(vec![#(#entries),*]) }
+        }
+        Fields::Unnamed(unnamed) => {
+            let items = (0..unnamed.unnamed.len()).map(|i| {
+                let binding = format_ident!("f{i}");
+                quote! { ::core::convert::Into::<wasm_offload::Val>::into(#binding) }
+            });
+            quote! { wasm_offload::Val::Tuple(vec![#(#items),*]) }
+        }
+        Fields::Unit => quote! { wasm_offload::Val::Tuple(vec![]) },
+    }
+}
+
+/// The pattern that binds all fields of `fields` for use with [`fields_into_val`].
+fn fields_pattern(fields: &Fields) -> TokenStream {
+    match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote! { { #(#idents),* } }
+        }
+        Fields::Unnamed(unnamed) => {
+            let bindings = (0..unnamed.unnamed.len()).map(|i| format_ident!("f{i}"));
+            quote! { ( #(#bindings),* ) }
+        }
+        Fields::Unit => quote! {},
+    }
+}
+
+/// Builds `constructor` with fields taken from the `Val` expression `val`.
+fn fields_from_val(fields: &Fields, constructor: TokenStream, val: TokenStream) -> TokenStream {
+    match fields {
+        Fields::Named(named) => {
+            let expected = "record";
+            let takes = named.named.iter().map(|f| {
+                let ident = f.ident.as_ref().unwrap();
+                let name = wit_name(ident);
+                quote! { #ident: fields.take(#name)? }
+            });
+            quote! {{
+                let mut fields = wasm_offload::RecordFields::new(#val, #expected)?;
+                Ok(#constructor { #(#takes),* })
+            }}
+        }
+        Fields::Unnamed(unnamed) => {
+            let tys = unnamed.unnamed.iter().map(|f| &f.ty);
+            let bindings: Vec<_> = (0..unnamed.unnamed.len())
+                .map(|i| format_ident!("f{i}"))
+                .collect();
+            quote! {{
+                let (#(#bindings,)*) =
+                    <(#(#tys,)*) as wasm_offload::FromVal>::from_val(#val)?;
+                Ok(#constructor(#(#bindings),*))
+            }}
+        }
+        Fields::Unit => quote! {{
+            <() as wasm_offload::FromVal>::from_val(#val)?;
+            Ok(#constructor)
+        }},
+    }
+}
+
+pub fn derive_into_val(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let generics = add_bounds(
+        &input.generics,
+        parse_quote!(::core::convert::Into<wasm_offload::Val>),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let pattern = fields_pattern(&data.fields);
+            let val = fields_into_val(&data.fields);
+            quote! {
+                let #name #pattern = value;
+                #val
+            }
+        }
+        Data::Enum(data) => {
+            let c_like = data
+                .variants
+                .iter()
+                .all(|v| matches!(v.fields, Fields::Unit));
+            let arms = data.variants.iter().map(|v| {
+                let ident = &v.ident;
+                let case = wit_name(ident);
+                let pattern = fields_pattern(&v.fields);
+                let payload = match &v.fields {
+                    Fields::Unit => quote! { None },
+                    Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => quote! {
+                        Some(Box::new(::core::convert::Into::<wasm_offload::Val>::into(f0)))
+                    },
+                    fields => {
+                        let val = fields_into_val(fields);
+                        quote! { Some(Box::new(#val)) }
+                    }
+                };
+                if c_like {
+                    quote! { #name::#ident => wasm_offload::Val::Enum(#case.to_string()) }
+                } else {
+                    quote! {
+                        #name::#ident #pattern => {
+                            wasm_offload::Val::Variant(#case.to_string(), #payload)
+                        }
+                    }
+                }
+            });
+            quote! {
+                match value {
+                    #(#arms,)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                &input,
+                "IntoVal cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::core::convert::From<#name #ty_generics> for wasm_offload::Val
+            #where_clause
+        {
+            #[allow(unused_variables)]
+            fn from(value: #name #ty_generics) -> Self {
+                #body
+            }
+        }
+    })
+}
+
+pub fn derive_from_val(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let generics = add_bounds(&input.generics, parse_quote!(wasm_offload::FromVal));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => fields_from_val(&data.fields, quote! { #name }, quote! { val }),
+        Data::Enum(data) => {
+            let c_like = data
+                .variants
+                .iter()
+                .all(|v| matches!(v.fields, Fields::Unit));
+            let cases: Vec<_> = data.variants.iter().map(|v| wit_name(&v.ident)).collect();
+            let expected = format!("one of {}", cases.join(", "));
+            let arms = data.variants.iter().zip(&cases).map(|(v, case)| {
+                let ident = &v.ident;
+                if c_like {
+                    return quote! { #case => Ok(#name::#ident) };
+                }
+                let payload = quote! {
+                    payload.map(|v| *v).unwrap_or(wasm_offload::Val::Tuple(vec![]))
+                };
+                let build = match &v.fields {
+                    Fields::Unit => quote! { Ok(#name::#ident) },
+                    Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+                        let ty = &unnamed.unnamed[0].ty;
+                        quote! {
+                            <#ty as wasm_offload::FromVal>::from_val(#payload)
+                                .map(#name::#ident)
+                                .map_err(|e| e.at(wasm_offload::PathSegment::Case(#case.to_string())))
+                        }
+                    }
+                    fields => {
+                        let build = fields_from_val(fields, quote! { #name::#ident }, payload);
+                        quote! {
+                            (|| -> Result<Self, wasm_offload::FromValError> { #build })()
+                                .map_err(|e| e.at(wasm_offload::PathSegment::Case(#case.to_string())))
+                        }
+                    }
+                };
+                quote! { #case => #build }
+            });
+            let (kind, pattern) = if c_like {
+                ("enum", quote! { wasm_offload::Val::Enum(case) })
+            } else {
+                (
+                    "variant",
+                    quote! { wasm_offload::Val::Variant(case, payload) },
+                )
+            };
+            quote! {
+                match val {
+                    #pattern => match case.as_str() {
+                        #(#arms,)*
+                        other => Err(wasm_offload::FromValError::custom(
+                            #expected,
+                            format!("case `{other}`"),
+                        )),
+                    },
+                    other => Err(wasm_offload::FromValError::new(#kind, &other)),
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                &input,
+                "FromVal cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics wasm_offload::FromVal for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn from_val(val: wasm_offload::Val) -> Result<Self, wasm_offload::FromValError> {
+                #body
+            }
+        }
+    })
+}
+
+/// The `ValType` of a payload made of `fields`.
+fn fields_val_type(fields: &Fields) -> TokenStream {
+    match fields {
+        Fields::Named(named) => {
+            let entries = named.named.iter().map(|f| {
+                let name = wit_name(f.ident.as_ref().unwrap());
+                let ty = &f.ty;
+                quote! { (#name.to_string(), <#ty as wasm_offload::HasValType>::val_type()) }
+            });
+            quote! { wasm_offload::ValType::Record(vec![#(#entries),*]) }
+        }
+        Fields::Unnamed(unnamed) => {
+            let tys = unnamed.unnamed.iter().map(|f| &f.ty);
+            quote! {
+                wasm_offload::ValType::Tuple(vec![#(<#tys as wasm_offload::HasValType>::val_type()),*])
+            }
+        }
+        Fields::Unit => quote! { wasm_offload::ValType::Tuple(vec![]) },
+    }
+}
+
+pub fn derive_has_val_type(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let generics = add_bounds(&input.generics, parse_quote!(wasm_offload::HasValType));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => fields_val_type(&data.fields),
+        Data::Enum(data) => {
+            let c_like = data
+                .variants
+                .iter()
+                .all(|v| matches!(v.fields, Fields::Unit));
+            if c_like {
+                let cases = data.variants.iter().map(|v| wit_name(&v.ident));
+                quote! { wasm_offload::ValType::Enum(vec![#(#cases.to_string()),*]) }
+            } else {
+                let cases = data.variants.iter().map(|v| {
+                    let case = wit_name(&v.ident);
+                    let payload = match &v.fields {
+                        Fields::Unit => quote! { None },
+                        Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+                            let ty = &unnamed.unnamed[0].ty;
+                            quote! { Some(<#ty as wasm_offload::HasValType>::val_type()) }
+                        }
+                        fields => {
+                            let ty = fields_val_type(fields);
+                            quote! { Some(#ty) }
+                        }
+                    };
+                    quote! { (#case.to_string(), #payload) }
+                });
+                quote! { wasm_offload::ValType::Variant(vec![#(#cases),*]) }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                &input,
+                "HasValType cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics wasm_offload::HasValType for #name #ty_generics #where_clause {
+            fn val_type() -> wasm_offload::ValType {
+                #body
+            }
+        }
+    })
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use proc_macro2::Span;
+use sha2::{Digest, Sha256};
+
+use crate::cache::{self, BuildCache, BuildInputs};
+
+/// Environment variable that overrides the directory guest crates are generated and built in.
+const BUILD_DIR_VAR: &str = "WASM_OFFLOAD_BUILD_DIR";
+
+/// Environment variable that, if set, makes expansions say why they build a guest crate.
+const LOG_VAR: &str = "WASM_OFFLOAD_LOG";
+
+/// The crate an offloaded function is compiled into.
+///
+/// Guest crates live outside of the source tree, in `wasm-offload/<package>/` under the
+/// target dir of the host or in `$WASM_OFFLOAD_BUILD_DIR`. The guest crates of a package share
+/// an explicit target dir, so that dependencies are only built once and an inherited
+/// `CARGO_TARGET_DIR` can't point the nested cargo at the target dir the outer build holds
+/// locked.
+pub(crate) struct GuestCrate {
+    name: String,
+    dir: PathBuf,
+    target_dir: PathBuf,
+    /// The default location of the [`BuildCache`].
+    cache_dir: PathBuf,
+    /// Where the built components are put.
+    package_dir: PathBuf,
+    /// Where errors building the crate are reported.
+    span: Span,
+}
+
+impl GuestCrate {
+    /// The guest crate for `function` of the package that is being compiled.
+    ///
+    /// The crate is named after the function and a hash of `signature` and where it is
+    /// defined, the `source` file and the line of `span`, so that every function gets a crate
+    /// of its own, even if functions of the same name and signature are defined in different
+    /// modules, while edits to its body keep reusing the crate. Errors are reported at `span`.
+    pub(crate) fn locate(
+        cargo_dir: &Path,
+        source: Option<&Path>,
+        function: &str,
+        signature: &str,
+        span: Span,
+    ) -> syn::Result<Self> {
+        let build_dir = match env::var_os(BUILD_DIR_VAR) {
+            Some(dir) => PathBuf::from(dir),
+            None => target_dir(cargo_dir)
+                .map_err(|err| syn::Error::new(span, err))?
+                .join("wasm-offload"),
+        };
+        let package = env::var("CARGO_PKG_NAME")
+            .map_err(|_| syn::Error::new(span, "`CARGO_PKG_NAME` is not set"))?;
+        let package_dir = build_dir.join(package);
+        // Paths within the package, so that the name doesn't depend on where it is checked out.
+        let module = source.map_or(Path::new(""), |file| {
+            file.strip_prefix(cargo_dir).unwrap_or(file)
+        });
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}:{}\0", module.display(), span.unwrap().line()));
+        hasher.update(signature);
+        let name = format!("{function}-{}", &cache::hex(&hasher.finalize())[..16]);
+        Ok(Self {
+            dir: package_dir.join(&name),
+            target_dir: package_dir.join("target"),
+            // Package names can't start with a dot, so this can't be a package dir.
+            cache_dir: build_dir.join(".cache"),
+            package_dir,
+            name,
+            span,
+        })
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> syn::Error {
+        syn::Error::new(self.span, message)
+    }
+
+    /// Like [`write_if_changed`], failing with an error that names `path`.
+    fn write_file(&self, path: &Path, contents: impl AsRef<[u8]>) -> syn::Result<()> {
+        write_if_changed(path, contents)
+            .map_err(|err| self.error(format!("could not write `{}`: {err}", path.display())))
+    }
+
+    fn source_file(&self) -> PathBuf {
+        self.dir.join("src").join("lib.rs")
+    }
+
+    fn manifest(&self) -> String {
+        format!(
+            r#"[package]
+name = "{}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+crate-type = ["cdylib"]
+
+[dependencies]
+wit-bindgen = "0.34"
+
+[workspace]
+"#,
+            self.name
+        )
+    }
+
+    /// Puts the component built from `source` and `wit` next to the guest crate and returns
+    /// its path. The component is taken from the build cache if one has been built from the
+    /// same inputs before. `types` is the contents of the types file the WIT types were taken
+    /// from. See [`Self::build`] for `remap`.
+    ///
+    /// The file is named after the cache key and never changes once written, so an expansion
+    /// running in parallel, e.g. in rust-analyzer, can't replace the component another
+    /// expansion includes with one built from different inputs.
+    pub(crate) fn component(
+        &self,
+        source: &str,
+        wit: &str,
+        types: Option<&str>,
+        remap: Option<&Path>,
+        debug: bool,
+    ) -> syn::Result<PathBuf> {
+        let manifest = self.manifest();
+        let mut inputs = BuildInputs::default();
+        inputs.add("source", source);
+        inputs.add("WIT", wit);
+        inputs.add("types file", types.unwrap_or_default());
+        inputs.add("manifest", &manifest);
+        // The toolchain is looked up where the crate is built.
+        fs::create_dir_all(&self.dir).map_err(|err| {
+            self.error(format!("could not create `{}`: {err}", self.dir.display()))
+        })?;
+        let toolchain = cache::toolchain(&self.dir).map_err(|err| self.error(err))?;
+        inputs.add("toolchain", toolchain);
+        inputs.add("build options", format!("{remap:?} {debug}"));
+
+        let cache = BuildCache::open(&self.cache_dir);
+        let key = inputs.key();
+        let component_file = self.package_dir.join(format!("{}-{key}.wasm", self.name));
+        if component_file.exists() {
+            return Ok(component_file);
+        }
+        if let Some(wasm) = cache.get(&key) {
+            self.write_file(&component_file, wasm)?;
+            return Ok(component_file);
+        }
+        let recorded = self.dir.join("inputs");
+        if env::var_os(LOG_VAR).is_some() {
+            let previous = fs::read_to_string(&recorded).ok();
+            eprintln!(
+                "wasm-offload: building `{}` because {}",
+                self.name,
+                inputs.rebuild_reason(previous.as_deref())
+            );
+        }
+        self.write(&manifest, source, wit)?;
+        let wasm = self.build(remap, debug)?;
+        cache.insert(&key, &wasm);
+        self.write_file(&recorded, inputs.encode())?;
+        self.write_file(&component_file, wasm)?;
+        Ok(component_file)
+    }
+
+    /// Writes the guest crate. Files that are already up to date are left alone, so that cargo
+    /// doesn't rebuild an unchanged guest.
+    fn write(&self, manifest: &str, source: &str, wit: &str) -> syn::Result<()> {
+        self.write_file(&self.dir.join("Cargo.toml"), manifest)?;
+        self.write_file(&self.source_file(), source)?;
+        self.write_file(&self.dir.join("wit").join("offloaded.wit"), wit)
+    }
+
+    /// Builds the guest crate and returns the component.
+    ///
+    /// `remap` names the file the guest source was taken from, so that panic locations and
+    /// debug info point at it instead of the generated crate. The remapping is only passed to
+    /// the guest crate itself, so that it doesn't replace the rustflags the user configured
+    /// and doesn't invalidate the dependencies the guests of a package share.
+    fn build(&self, remap: Option<&Path>, debug: bool) -> syn::Result<Vec<u8>> {
+        let mut build = Command::new("cargo");
+        build
+            .current_dir(&self.dir)
+            .args(["rustc", "--release", "--target", "wasm32-wasip2"])
+            .arg("--target-dir")
+            .arg(&self.target_dir);
+        if let Some(file) = remap {
+            let file = file.display();
+            build
+                .arg("--")
+                .arg("--remap-path-prefix")
+                .arg(format!("src/lib.rs={file}"))
+                .arg("--remap-path-prefix")
+                .arg(format!("{}={file}", self.source_file().display()));
+        }
+        if debug {
+            build.env("CARGO_PROFILE_RELEASE_DEBUG", "true");
+        }
+        let status = build
+            .status()
+            .map_err(|err| self.error(format!("could not run cargo: {err}")))?;
+        if !status.success() {
+            return Err(self.error(format!(
+                "building the guest crate `{}` failed, see the cargo output above",
+                self.name
+            )));
+        }
+
+        let wasm = self
+            .target_dir
+            .join("wasm32-wasip2")
+            .join("release")
+            .join(format!("{}.wasm", self.name.replace('-', "_")));
+        fs::read(&wasm)
+            .map_err(|err| self.error(format!("could not read `{}`: {err}", wasm.display())))
+    }
+}
+
+/// The target dir of the workspace the package being compiled belongs to.
+///
+/// Locating the workspace runs cargo, so the result is remembered for the rest of the
+/// compilation, or for the lifetime of the proc macro server of an IDE.
+fn target_dir(cargo_dir: &Path) -> Result<PathBuf, String> {
+    static TARGET_DIRS: Mutex<BTreeMap<PathBuf, PathBuf>> = Mutex::new(BTreeMap::new());
+
+    if let Some(dir) = env::var_os("CARGO_TARGET_DIR") {
+        // Relative paths are taken relative to the working directory of rustc, which is the
+        // workspace root.
+        let cwd = env::current_dir()
+            .map_err(|err| format!("could not get the working directory: {err}"))?;
+        return Ok(cwd.join(dir));
+    }
+    let mut target_dirs = TARGET_DIRS.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(dir) = target_dirs.get(cargo_dir) {
+        return Ok(dir.clone());
+    }
+    let output = Command::new("cargo")
+        .current_dir(cargo_dir)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .output()
+        .map_err(|err| format!("could not run cargo: {err}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "could not locate the workspace: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let manifest = String::from_utf8_lossy(&output.stdout);
+    let dir = Path::new(manifest.trim())
+        .parent()
+        .ok_or("the workspace manifest is not in a directory")?
+        .join("target");
+    target_dirs.insert(cargo_dir.to_path_buf(), dir.clone());
+    Ok(dir)
+}
+
+/// Replaces the contents of `path` with `contents` unless they are equal already.
+///
+/// The file is written next to `path` and then renamed, so that macro expansions running in
+/// parallel, e.g. in rust-analyzer and cargo, never see a half-written file.
+pub(crate) fn write_if_changed(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+    let contents = contents.as_ref();
+    if fs::read(path).is_ok_and(|current| current == contents) {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temp = path.with_extension(format!(
+        "{}.{}.tmp",
+        process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)
+}
@@ -0,0 +1,73 @@
+use proc_macro2::{Delimiter, Spacing, Span, TokenStream, TokenTree};
+
+/// Prints tokens as source code, putting every token on the line it has in the file the macro
+/// was invoked from.
+///
+/// The guest crate is compiled from the printed source, so line numbers in guest panic
+/// messages and debug info match the original function.
+pub(crate) struct LinePrinter {
+    out: String,
+    line: usize,
+}
+
+impl LinePrinter {
+    pub(crate) fn new() -> Self {
+        Self {
+            out: String::new(),
+            line: 1,
+        }
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.out
+    }
+
+    /// Appends `tokens`. Tokens whose line has already been passed, such as tokens created
+    /// by the macro itself, are put on the current line.
+    pub(crate) fn print(&mut self, tokens: TokenStream) {
+        for token in tokens {
+            match token {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.advance(group.span_open());
+                    self.push(open);
+                    self.print(group.stream());
+                    self.advance(group.span_close());
+                    self.push(close);
+                    self.push(" ");
+                }
+                TokenTree::Punct(punct) => {
+                    self.advance(punct.span());
+                    self.push(&punct.as_char().to_string());
+                    if punct.spacing() == Spacing::Alone {
+                        self.push(" ");
+                    }
+                }
+                token => {
+                    self.advance(token.span());
+                    self.push(&token.to_string());
+                    self.push(" ");
+                }
+            }
+        }
+    }
+
+    fn advance(&mut self, span: Span) {
+        let line = span.unwrap().line();
+        while self.line < line {
+            self.out.push('\n');
+            self.line += 1;
+        }
+    }
+
+    fn push(&mut self, text: &str) {
+        // Multi-line string literals.
+        self.line += text.matches('\n').count();
+        self.out.push_str(text);
+    }
+}
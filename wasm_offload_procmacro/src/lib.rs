@@ -1,9 +1,16 @@
-use std::{collections::HashMap, io::Write, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use heck::ToUpperCamelCase;
+use heck::ToKebabCase;
 use proc_macro::TokenStream;
+use proc_macro2::Span;
 use quote::{format_ident, quote, ToTokens};
-use syn::{ExprAssign, FnArg, GenericArgument, ItemFn, PathArguments, ReturnType};
+use syn::{
+    ext::IdentExt, parse::Parser, DeriveInput, FnArg, GenericArgument, Ident, ItemFn, ItemMod,
+    LitStr, PathArguments, ReturnType, Signature, Visibility,
+};
 use wit_encoder::{
     Field, Interface, Package, PackageName, StandaloneFunc, TypeDef, Use, World, WorldItem,
     WorldNamedInterface,
@@ -11,126 +18,200 @@ use wit_encoder::{
 
 extern crate proc_macro;
 
+mod cache;
+mod derive;
+mod guest;
+mod layout;
+
 #[derive(Default)]
 struct TypeContext {
     types: HashMap<String, Vec<Field>>,
+    structs: Vec<syn::ItemStruct>,
+    /// Types that are defined next to the functions but have no WIT equivalent yet, such as
+    /// enums and type aliases.
+    untranslated: HashMap<String, &'static str>,
 }
 
 impl TypeContext {
-    fn add_struct_type(&mut self, ty: syn::ItemStruct) {
+    fn add_struct_type(&mut self, ty: syn::ItemStruct) -> syn::Result<()> {
         let mut fields = vec![];
         let ty_name = ty.ident.to_string().to_lowercase().replace('_', "-");
-        for field in ty.fields {
-            let f_name = field
-                .ident
-                .expect("Tuple structs are currently unsupported")
-                .to_string();
-            let f_ty = self.to_wit_type(&field.ty).unwrap();
-            fields.push(wit_encoder::Field::new(f_name, f_ty));
+        for field in &ty.fields {
+            let Some(ident) = &field.ident else {
+                return Err(syn::Error::new_spanned(
+                    &ty.fields,
+                    "tuple structs are not supported yet",
+                ));
+            };
+            let f_ty = self.wit_type(&field.ty)?;
+            fields.push(wit_encoder::Field::new(
+                ident.to_string().to_kebab_case(),
+                f_ty,
+            ));
         }
+        self.structs.push(ty);
         self.types.insert(ty_name, fields);
+        Ok(())
     }
 
-    fn to_wit_type(&mut self, input: &syn::Type) -> Option<wit_encoder::Type> {
-        match input {
-            syn::Type::Array(_) => todo!(),
-            syn::Type::BareFn(_) => todo!(),
-            syn::Type::Group(_) => todo!(),
-            syn::Type::ImplTrait(_) => todo!(),
-            syn::Type::Infer(_) => todo!(),
-            syn::Type::Macro(_) => todo!(),
-            syn::Type::Never(_) => todo!(),
-            syn::Type::Paren(_) => todo!(),
-            syn::Type::Path(p) => {
-                let t = p.path.segments.last().unwrap();
-                let t_id = t.ident.to_string();
-                match t_id.as_str() {
-                    "i8" => Some(wit_encoder::Type::S8),
-                    "i16" => Some(wit_encoder::Type::S16),
-                    "i32" => Some(wit_encoder::Type::S32),
-                    "i64" => Some(wit_encoder::Type::S64),
-                    "u8" => Some(wit_encoder::Type::U8),
-                    "u16" => Some(wit_encoder::Type::U16),
-                    "u32" => Some(wit_encoder::Type::U32),
-                    "u64" => Some(wit_encoder::Type::U64),
-                    "usize" => Some(wit_encoder::Type::U64),
-                    "isize" => Some(wit_encoder::Type::S64),
-                    "f32" => Some(wit_encoder::Type::F32),
-                    "f64" => Some(wit_encoder::Type::F64),
-                    "String" => Some(wit_encoder::Type::String),
-                    "Vec" | "Option" => {
-                        let PathArguments::AngleBracketed(ab) = &t.arguments else {
-                            return None;
-                        };
-                        let arg = ab.args.first().unwrap();
-                        if let GenericArgument::Type(ty) = arg {
-                            self.to_wit_type(ty).map(|t| match t_id.as_str() {
-                                "Vec" => wit_encoder::Type::list(t),
-                                "Option" => wit_encoder::Type::option(t),
-                                _ => unreachable!(),
-                            })
-                        } else {
-                            None
-                        }
-                    }
-                    "Result" => {
-                        let PathArguments::AngleBracketed(ab) = &t.arguments else {
-                            return None;
-                        };
-                        let mut args = ab.args.iter().take(2);
-                        let ok_arg = args.next().unwrap();
-                        let err_arg = args.next().unwrap();
-                        if let (GenericArgument::Type(o_ty), GenericArgument::Type(e_ty)) =
-                            (ok_arg, err_arg)
-                        {
-                            let wo_ty = self.to_wit_type(o_ty).unwrap();
-                            let we_ty = self.to_wit_type(e_ty).unwrap();
-                            Some(wit_encoder::Type::result_both(wo_ty, we_ty))
-                        } else {
-                            None
-                        }
-                    }
-                    id => {
-                        // println!("{id}");
-                        // None
-                        Some(wit_encoder::Type::named(id.to_string().to_lowercase()))
-                    }
+    /// The WIT type `input` is passed as.
+    fn wit_type(&self, input: &syn::Type) -> syn::Result<wit_encoder::Type> {
+        let unsupported = || {
+            syn::Error::new_spanned(
+                input,
+                "this type can't be passed to or from an offloaded function",
+            )
+        };
+        let syn::Type::Path(p) = input else {
+            return Err(unsupported());
+        };
+        let t = p.path.segments.last().ok_or_else(unsupported)?;
+        let t_id = t.ident.to_string();
+        // The type arguments of generic types such as `Vec<T>`.
+        let args = || -> syn::Result<Vec<wit_encoder::Type>> {
+            let PathArguments::AngleBracketed(ab) = &t.arguments else {
+                return Err(unsupported());
+            };
+            ab.args
+                .iter()
+                .map(|arg| match arg {
+                    GenericArgument::Type(ty) => self.wit_type(ty),
+                    _ => Err(unsupported()),
+                })
+                .collect()
+        };
+        Ok(match t_id.as_str() {
+            "i8" => wit_encoder::Type::S8,
+            "i16" => wit_encoder::Type::S16,
+            "i32" => wit_encoder::Type::S32,
+            "i64" => wit_encoder::Type::S64,
+            "u8" => wit_encoder::Type::U8,
+            "u16" => wit_encoder::Type::U16,
+            "u32" => wit_encoder::Type::U32,
+            "u64" => wit_encoder::Type::U64,
+            "usize" => wit_encoder::Type::U64,
+            "isize" => wit_encoder::Type::S64,
+            "f32" => wit_encoder::Type::F32,
+            "f64" => wit_encoder::Type::F64,
+            "String" => wit_encoder::Type::String,
+            "Vec" | "Option" | "Result" => match (t_id.as_str(), &args()?[..]) {
+                ("Vec", [t]) => wit_encoder::Type::list(t.clone()),
+                ("Option", [t]) => wit_encoder::Type::option(t.clone()),
+                ("Result", [o, e]) => wit_encoder::Type::result_both(o.clone(), e.clone()),
+                _ => return Err(unsupported()),
+            },
+            id => {
+                if let Some(kind) = self.untranslated.get(id) {
+                    return Err(syn::Error::new_spanned(
+                        input,
+                        format!("{kind} can't be passed to or from an offloaded function yet"),
+                    ));
                 }
+                wit_encoder::Type::named(id.to_lowercase())
             }
-            syn::Type::Ptr(_) => todo!(),
-            syn::Type::Reference(_) => todo!(),
-            syn::Type::Slice(_) => todo!(),
-            syn::Type::TraitObject(_) => todo!(),
-            syn::Type::Tuple(_) => todo!(),
-            syn::Type::Verbatim(_) => todo!(),
-            _ => None,
-        }
+        })
     }
-}
 
-fn impl_ty_into_val(ty: (&str, &[Field])) -> proc_macro2::TokenStream {
-    let (name, fields) = ty;
-    let ty_name = format_ident!("{}", name.to_upper_camel_case());
-    let fields = fields.iter().map(|f| {
-        let f_name = f.name().to_string();
-        let f_name_id = format_ident!("{}", f_name);
-        quote! {
-            value.push((#f_name.to_string(), self.#f_name_id.into()));
-        }
-    });
-    quote! {
-        impl Into<wasm_offload::Val> for #ty_name {
-            fn into(self) -> wasm_offload::Val {
-                let mut value = vec![];
-                #(#fields)*
-                wasm_offload::Val::Record(value)
+    /// An expression that builds the `wasm_offload::ValType` of the WIT type `input` is passed
+    /// as, with the records of `types` spelled out.
+    fn val_type(&self, input: &syn::Type) -> syn::Result<proc_macro2::TokenStream> {
+        self.wit_val_type(&self.wit_type(input)?, input)
+    }
+
+    fn wit_val_type(
+        &self,
+        ty: &wit_encoder::Type,
+        input: &syn::Type,
+    ) -> syn::Result<proc_macro2::TokenStream> {
+        use wit_encoder::Type;
+        let boxed = |ty: &Option<Type>| -> syn::Result<proc_macro2::TokenStream> {
+            Ok(match ty {
+                Some(ty) => {
+                    let ty = self.wit_val_type(ty, input)?;
+                    quote! { Some(Box::new(#ty)) }
+                }
+                None => quote! { None },
+            })
+        };
+        Ok(match ty {
+            Type::Bool => quote! { wasm_offload::ValType::Bool },
+            Type::U8 => quote! { wasm_offload::ValType::U8 },
+            Type::U16 => quote! { wasm_offload::ValType::U16 },
+            Type::U32 => quote! { wasm_offload::ValType::U32 },
+            Type::U64 => quote! { wasm_offload::ValType::U64 },
+            Type::S8 => quote! { wasm_offload::ValType::S8 },
+            Type::S16 => quote! { wasm_offload::ValType::S16 },
+            Type::S32 => quote! { wasm_offload::ValType::S32 },
+            Type::S64 => quote! { wasm_offload::ValType::S64 },
+            Type::F32 => quote! { wasm_offload::ValType::Float32 },
+            Type::F64 => quote! { wasm_offload::ValType::Float64 },
+            Type::Char => quote! { wasm_offload::ValType::Char },
+            Type::String => quote! { wasm_offload::ValType::String },
+            Type::List(ty) => {
+                let ty = self.wit_val_type(ty, input)?;
+                quote! { wasm_offload::ValType::List(Box::new(#ty)) }
             }
-        }
+            Type::Option(ty) => {
+                let ty = self.wit_val_type(ty, input)?;
+                quote! { wasm_offload::ValType::Option(Box::new(#ty)) }
+            }
+            Type::Result(result) => {
+                let ok = boxed(result.get_ok())?;
+                let err = boxed(result.get_err())?;
+                quote! { wasm_offload::ValType::Result { ok: #ok, err: #err } }
+            }
+            Type::Tuple(tuple) => {
+                let tys = tuple
+                    .types()
+                    .iter()
+                    .map(|ty| self.wit_val_type(ty, input))
+                    .collect::<syn::Result<Vec<_>>>()?;
+                quote! { wasm_offload::ValType::Tuple(vec![#(#tys),*]) }
+            }
+            Type::Named(name) => {
+                let fields = self.types.get(name.raw_name()).ok_or_else(|| {
+                    syn::Error::new_spanned(input, format!("unknown type `{name}`"))
+                })?;
+                let fields = fields
+                    .iter()
+                    .map(|field| {
+                        let name = field.name().raw_name();
+                        let ty = self.wit_val_type(field.type_(), input)?;
+                        Ok(quote! { (#name.to_string(), #ty) })
+                    })
+                    .collect::<syn::Result<Vec<_>>>()?;
+                quote! { wasm_offload::ValType::Record(vec![#(#fields),*]) }
+            }
+            Type::Borrow(_) => {
+                return Err(syn::Error::new_spanned(
+                    input,
+                    "this type can't be passed to or from an offloaded function",
+                ))
+            }
+        })
     }
 }
 
-fn create_component_source(input: ItemFn) -> proc_macro2::TokenStream {
-    quote! {
+/// Prints the guest crate source, with `items` at the top level and `exports` as the functions
+/// of the `Guest` implementation.
+fn create_component_source(items: Vec<syn::Item>, exports: Vec<ItemFn>) -> String {
+    let exports = exports.into_iter().map(|mut export| {
+        export
+            .block
+            .stmts
+            .insert(0, syn::parse_quote! { install_panic_hook(); });
+        export
+    });
+    // Guest code comes first, so that it can be put on its original lines.
+    let mut printer = layout::LinePrinter::new();
+    printer.print(quote! {
+        #(#items)*
+
+        impl bindings::Guest for Component {
+            #(#exports)*
+        }
+    });
+    printer.print(quote! {
         mod bindings {
             wit_bindgen::generate!({
                 world: "offload"
@@ -139,16 +220,32 @@ fn create_component_source(input: ItemFn) -> proc_macro2::TokenStream {
 
         use bindings::*;
 
+        /// Passes panics on to the host, which otherwise only sees an `unreachable` trap.
+        fn install_panic_hook() {
+            static HOOK: std::sync::Once = std::sync::Once::new();
+            HOOK.call_once(|| {
+                std::panic::set_hook(Box::new(|info| {
+                    let payload = info.payload();
+                    let message = payload
+                        .downcast_ref::<&str>()
+                        .map(|message| message.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+                    let (file, line) = info
+                        .location()
+                        .map_or(("<unknown>", 0), |location| (location.file(), location.line()));
+                    report_panic(&message, file, line);
+                }));
+            });
+        }
+
         pub struct Component;
         bindings::export!(Component with_types_in bindings);
-
-        impl bindings::Guest for Component {
-            #input
-        }
-    }
+    });
+    printer.finish()
 }
 
-fn create_wit_bindings(ctx: &mut TypeContext, input: ItemFn) -> String {
+fn create_wit_bindings(ctx: &TypeContext, functions: &[ItemFn]) -> syn::Result<String> {
     let mut pkg = Package::new(PackageName::new("local", "offload", None));
 
     let mut world = World::new("offload");
@@ -158,181 +255,567 @@ fn create_wit_bindings(ctx: &mut TypeContext, input: ItemFn) -> String {
         types_intf.type_def(TypeDef::record(name.clone(), ty.clone()));
     }
 
-    let fn_name = input.sig.ident.to_string().replace("_", "-");
-    let mut func = StandaloneFunc::new(fn_name);
+    let mut funcs = vec![];
+    for input in functions {
+        let fn_name = input.sig.ident.to_string().replace("_", "-");
+        let mut func = StandaloneFunc::new(fn_name);
 
-    let params = func.params_mut();
-    for param in input.sig.inputs {
-        let FnArg::Typed(param) = param else {
-            unimplemented!()
-        };
+        let params = func.params_mut();
+        for param in &input.sig.inputs {
+            let FnArg::Typed(param) = param else {
+                return Err(syn::Error::new_spanned(param, "methods can't be offloaded"));
+            };
 
-        let name = param.pat;
-        let ty = ctx
-            .to_wit_type(&param.ty)
-            .expect(&format!("Failed to translate type {:?}", param.ty));
-        params.push(quote! {#name}.to_string(), ty);
-    }
+            let name = &param.pat;
+            params.push(quote! {#name}.to_string(), ctx.wit_type(&param.ty)?);
+        }
 
-    if let ReturnType::Type(_, ty) = input.sig.output {
-        func.set_results(
-            ctx.to_wit_type(&ty)
-                .expect(&format!("Failed to translate type {:?}", ty)),
-        );
+        if let ReturnType::Type(_, ty) = &input.sig.output {
+            func.set_results(ctx.wit_type(ty)?);
+        }
+        funcs.push(func);
     }
 
-    let func_item = WorldItem::function_export(func);
-    for (ty, _) in &ctx.types {
+    // Called by the panic hook installed in `create_component_source`.
+    let mut report_panic = StandaloneFunc::new("report-panic");
+    let params = report_panic.params_mut();
+    params.push("message", wit_encoder::Type::String);
+    params.push("file", wit_encoder::Type::String);
+    params.push("line", wit_encoder::Type::U32);
+    world.item(WorldItem::function_import(report_panic));
+
+    for ty in ctx.types.keys() {
         let mut use_itm = Use::new("types");
         use_itm.item(ty.clone(), None);
         world.use_(use_itm);
     }
-    world.item(func_item);
+    for func in funcs {
+        world.item(WorldItem::function_export(func));
+    }
 
     pkg.interface(types_intf);
     world.named_interface_export(WorldNamedInterface::new("types"));
 
     pkg.world(world);
 
-    pkg.to_string()
+    Ok(pkg.to_string())
 }
 
-#[proc_macro_attribute]
-pub fn offload(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let mut ctx = TypeContext::default();
-    let attr = syn::parse_macro_input!(attr as ExprAssign);
-
-    let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
-
-    let left = attr.left.to_token_stream().to_string();
-    let right = attr.right.to_token_stream().to_string();
-    match left.as_str() {
-        "types" => {
-            let file = right.trim_matches('"');
-            let mut path =
-                PathBuf::from_str(&cargo_dir).expect("CARGO_MANIFEST_DIR must be set and valid");
-            path.push("src");
-            path.push(file);
-            let types_file =
-                std::fs::read_to_string(&path).expect(&format!("Could not read `{path:?}`",));
-            let types = syn::parse_file(&types_file).expect("Failed to parse types file");
-            for item in types.items {
-                match item {
-                    syn::Item::Enum(item_enum) => todo!(),
-                    syn::Item::Struct(item_struct) => {
-                        ctx.add_struct_type(item_struct);
-                    }
-                    syn::Item::Union(item_union) => todo!(),
-                    _ => {
-                        panic!("Items other than type definitions are not supported in type files")
-                    }
-                }
+/// Options given to `#[offload(...)]`.
+#[derive(Default)]
+struct OffloadOptions {
+    /// File under `src/` with the type definitions used by the function.
+    types: Option<String>,
+    /// Generate an `async` wrapper that calls `ASYNC_OFFLOADER`.
+    is_async: bool,
+    /// Build the guest with debug info, so that wasm backtraces can be symbolized.
+    debug: bool,
+}
+
+impl OffloadOptions {
+    fn parse(attr: TokenStream) -> syn::Result<Self> {
+        let mut options = Self::default();
+        let parser = syn::meta::parser(|meta| {
+            if meta.path.is_ident("async") {
+                options.is_async = true;
+                Ok(())
+            } else if meta.path.is_ident("debug") {
+                options.debug = true;
+                Ok(())
+            } else if meta.path.is_ident("types") {
+                options.types = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                let name = meta.path.to_token_stream().to_string();
+                Err(meta.error(format!("Unrecognized option: `{name}`")))
+            }
+        });
+        parser.parse(attr)?;
+        Ok(options)
+    }
+}
+
+/// Reads the type definitions from the `types` file of `options` into `ctx` and returns the
+/// contents of the file.
+fn load_types(
+    ctx: &mut TypeContext,
+    cargo_dir: &str,
+    options: &OffloadOptions,
+) -> syn::Result<Option<String>> {
+    let Some(file) = &options.types else {
+        return Ok(None);
+    };
+    let path = Path::new(cargo_dir).join("src").join(file);
+    let types_file = std::fs::read_to_string(&path).map_err(|err| {
+        syn::Error::new(
+            Span::call_site(),
+            format!("could not read `{}`: {err}", path.display()),
+        )
+    })?;
+    let types = syn::parse_file(&types_file).map_err(|err| {
+        syn::Error::new(
+            Span::call_site(),
+            format!("could not parse `{}`: {err}", path.display()),
+        )
+    })?;
+    for item in types.items {
+        match item {
+            syn::Item::Struct(item_struct) => ctx.add_struct_type(item_struct)?,
+            syn::Item::Enum(item_enum) => {
+                return Err(syn::Error::new_spanned(
+                    item_enum.enum_token,
+                    format!("enums in `{file}` are not supported yet"),
+                ))
+            }
+            syn::Item::Union(item_union) => {
+                return Err(syn::Error::new_spanned(
+                    item_union.union_token,
+                    format!("unions in `{file}` are not supported"),
+                ))
+            }
+            item => {
+                return Err(syn::Error::new_spanned(
+                    item,
+                    format!("`{file}` may only contain type definitions"),
+                ))
             }
-        }
-        other => {
-            panic!("Unrecognized option: `{other}`")
         }
     }
+    Ok(Some(types_file))
+}
 
-    let input = syn::parse_macro_input!(item as ItemFn);
+/// The directory of the manifest of the package being compiled.
+fn cargo_dir() -> syn::Result<String> {
+    std::env::var("CARGO_MANIFEST_DIR")
+        .map_err(|_| syn::Error::new(Span::call_site(), "`CARGO_MANIFEST_DIR` is not set"))
+}
 
-    if !std::fs::exists(format!("{cargo_dir}/offloaded")).unwrap() {
-        std::process::Command::new("cargo")
-            .current_dir(&cargo_dir)
-            .args(["new", "--lib", "offloaded"])
-            .spawn()
-            .unwrap()
-            .wait()
-            .unwrap();
-        let mut manifest = std::fs::OpenOptions::new()
-            .write(true)
-            .open(format!("{cargo_dir}/offloaded/Cargo.toml"))
-            .unwrap();
-        manifest
-            .write_all(
-                r#"
-[package]
-name = "offloaded"
-version = "0.1.0"
-edition = "2021"
-
-[lib]
-crate-type = ["cdylib"]
-
-[dependencies]
-
-[workspace]
-"#
-                .as_bytes(),
-            )
-            .unwrap();
-        std::process::Command::new("cargo")
-            .current_dir(format!("{cargo_dir}/offloaded"))
-            .args(["add", "wit-bindgen"])
-            .spawn()
-            .unwrap()
-            .wait()
-            .unwrap();
+/// The file `ident` was defined in, if it is a local file.
+fn source_file(ident: &Ident) -> syn::Result<Option<PathBuf>> {
+    let Some(file) = ident.span().unwrap().local_file() else {
+        return Ok(None);
+    };
+    // Relative paths are relative to the working directory of rustc.
+    let cwd = std::env::current_dir().map_err(|err| {
+        syn::Error::new(
+            ident.span(),
+            format!("could not get the working directory: {err}"),
+        )
+    })?;
+    Ok(Some(cwd.join(file)))
+}
+
+/// The `IntoVal` and `FromVal` implementations of the types in `ctx`. Their `ValType`s come
+/// from the WIT types instead, see [`TypeContext::val_type`].
+fn value_impls(ctx: &TypeContext) -> impl Iterator<Item = proc_macro2::TokenStream> + '_ {
+    ctx.structs.iter().map(|ty| {
+        let input = DeriveInput::from(ty.clone());
+        let into_val = derive::derive_into_val(input.clone()).unwrap();
+        let from_val = derive::derive_from_val(input).unwrap();
+        quote! {
+            #into_val
+            #from_val
+        }
+    })
+}
+
+/// The statics that hold the component at `path` and its handles in the offload targets of
+/// the synchronous and async wrappers that use it.
+fn module_statics(path: &Path, sync: bool, is_async: bool) -> proc_macro2::TokenStream {
+    let path = path.to_str().expect("component path must be UTF-8");
+    // Compiled on the first call, then reused. Only successful loads are stored, so a call
+    // after a failed load tries again.
+    let module = sync.then(|| {
+        quote! {
+            static MODULE: std::sync::OnceLock<wasm_offload::ModuleHandle> =
+                std::sync::OnceLock::new();
+        }
+    });
+    let async_module = is_async.then(|| {
+        quote! {
+            static ASYNC_MODULE: std::sync::OnceLock<wasm_offload::ModuleHandle> =
+                std::sync::OnceLock::new();
+        }
+    });
+    // Including the file keeps large components out of the token stream, and makes cargo
+    // rebuild the host when the component changes.
+    quote! {
+        static WASM: &[u8] = include_bytes!(#path);
+        #module
+        #async_module
     }
+}
 
-    std::fs::write(
-        format!("{cargo_dir}/offloaded/src/lib.rs"),
-        create_component_source(input.clone()).to_string(),
-    )
-    .unwrap();
-    std::fs::create_dir_all(format!("{cargo_dir}/offloaded/wit")).unwrap();
-    std::fs::write(
-        format!("{cargo_dir}/offloaded/wit/offloaded.wit"),
-        create_wit_bindings(&mut ctx, input.clone()),
-    )
-    .unwrap();
-    std::process::Command::new("cargo")
-        .current_dir(format!("{cargo_dir}/offloaded"))
-        .args(["build", "--release", "--target", "wasm32-wasip2"])
-        .spawn()
-        .unwrap()
-        .wait()
-        .unwrap();
-
-    let wasm_output = std::fs::read(format!(
-        "{cargo_dir}/offloaded/target/wasm32-wasip2/release/offloaded.wasm"
-    ))
-    .unwrap();
-
-    // std::fs::remove_dir_all(concat!(env!("CARGO_MANIFEST_DIR"), "/offloaded")).unwrap();
-    let impls = ctx.types.iter().map(|(ty, f)| impl_ty_into_val((ty, f)));
-
-    let fn_sig = input.sig;
+/// The host function that calls the guest export with the signature `fn_sig`. The component
+/// is taken from the statics of [`module_statics`], which `statics` declares unless they are
+/// already in scope.
+///
+/// The arguments are checked against the WIT signature of the export before they are sent.
+fn host_wrapper(
+    ctx: &TypeContext,
+    fn_sig: Signature,
+    is_async: bool,
+    statics: &proc_macro2::TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
     let fn_name = fn_sig.ident;
     let fn_name_str = fn_name.to_string().replace("_", "-");
     let fn_args = fn_sig.inputs;
-    let fn_params = fn_args.iter().map(|p| match p {
-        syn::FnArg::Receiver(_) => panic!("Cannot offload methods"),
-        syn::FnArg::Typed(t) => &t.pat,
-    });
-
-    let output = match fn_sig.output {
-        ReturnType::Default => quote! {
-            #(#impls)*
-
-            pub fn #fn_name(#fn_args) -> Result<(), Box<dyn std::error::Error>> {
-                let wasm = vec![#(#wasm_output),*];
-                let res = OFFLOADER.lock()?.call_function(&wasm, #fn_name_str, &[#(Val::from(#fn_params)),*], false)?;
-                Ok(())
+    let (fn_params, fn_param_tys): (Vec<_>, Vec<_>) = fn_args
+        .iter()
+        .map(|p| match p {
+            syn::FnArg::Receiver(receiver) => Err(syn::Error::new_spanned(
+                receiver,
+                "methods can't be offloaded",
+            )),
+            syn::FnArg::Typed(t) => Ok((&t.pat, &t.ty)),
+        })
+        .collect::<syn::Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+    let fn_param_names = fn_params.iter().map(|p| quote! {#p}.to_string());
+    let fn_param_val_tys = fn_param_tys
+        .iter()
+        .map(|ty| ctx.val_type(ty))
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let call = |returns: bool| {
+        let dispatch = if is_async {
+            quote! {
+                let module = match ASYNC_MODULE.get() {
+                    Some(module) => *module,
+                    None => {
+                        let module =
+                            wasm_offload::AsyncOffloadTarget::load_module(&*ASYNC_OFFLOADER, WASM)
+                                .await
+                                .map_err(wasm_offload::OffloadError::target)?;
+                        *ASYNC_MODULE.get_or_init(|| module)
+                    }
+                };
+                wasm_offload::AsyncOffloadTarget::call(
+                    &*ASYNC_OFFLOADER,
+                    module,
+                    #fn_name_str,
+                    &args,
+                    #returns,
+                )
+                .await
+                .map_err(wasm_offload::OffloadError::target)?
             }
-        },
-        ReturnType::Type(_, _) => {
+        } else {
             quote! {
-                #(#impls)*
+                let module = match MODULE.get() {
+                    Some(module) => *module,
+                    None => {
+                        let module =
+                            wasm_offload::SharedOffloadTarget::load_module(&*OFFLOADER, WASM)
+                                .map_err(Into::<wasm_offload::OffloadError>::into)?;
+                        *MODULE.get_or_init(|| module)
+                    }
+                };
+                wasm_offload::SharedOffloadTarget::call(
+                    &*OFFLOADER,
+                    module,
+                    #fn_name_str,
+                    &args,
+                    #returns,
+                )
+                .map_err(Into::<wasm_offload::OffloadError>::into)?
+            }
+        };
+        quote! {{
+            static PARAMS: std::sync::LazyLock<Vec<(&str, wasm_offload::ValType)>> =
+                std::sync::LazyLock::new(|| {
+                    vec![#((#fn_param_names, #fn_param_val_tys)),*]
+                });
+            let args: Vec<wasm_offload::Val> = vec![#(#fn_params.into()),*];
+            wasm_offload::type_check_args(#fn_name_str, &PARAMS, &args)?;
+
+            #statics
+            #dispatch
+        }}
+    };
 
-                pub fn #fn_name(#fn_args) -> Result<wasm_offload::Val, Box<dyn std::error::Error>> {
-                    let wasm = vec![#(#wasm_output),*];
-                    let res = OFFLOADER.lock()?.call_function(&wasm, #fn_name_str, &[#(#fn_params.into()),*], true)?;
-                    Ok(res.unwrap())
+    let asyncness = is_async.then(|| quote! { async });
+    Ok(match fn_sig.output {
+        ReturnType::Default => {
+            let call_unit = call(false);
+            quote! {
+                pub #asyncness fn #fn_name(#fn_args) -> Result<(), wasm_offload::OffloadError> {
+                    #call_unit;
+                    Ok(())
                 }
             }
         }
+        ReturnType::Type(_, ret_ty) => {
+            let call_ret = call(true);
+            quote! {
+                pub #asyncness fn #fn_name(#fn_args) -> Result<#ret_ty, wasm_offload::OffloadError> {
+                    let res = #call_ret;
+                    let res = res.ok_or_else(|| {
+                        wasm_offload::OffloadError::MissingResult(#fn_name_str.to_string())
+                    })?;
+                    <#ret_ty as wasm_offload::FromVal>::from_val(res).map_err(|source| {
+                        wasm_offload::OffloadError::InvalidResult {
+                            function: #fn_name_str.to_string(),
+                            source,
+                        }
+                    })
+                }
+            }
+        }
+    })
+}
+
+#[proc_macro_attribute]
+pub fn offload(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let options = match OffloadOptions::parse(attr) {
+        Ok(options) => options,
+        Err(e) => return e.into_compile_error().into(),
     };
+    let input = syn::parse_macro_input!(item as ItemFn);
+    expand_offload(&options, input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_offload(
+    options: &OffloadOptions,
+    input: ItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let mut ctx = TypeContext::default();
+    let cargo_dir = cargo_dir()?;
+    let types = load_types(&mut ctx, &cargo_dir, options)?;
+
+    let is_async = options.is_async || input.sig.asyncness.is_some();
+    // The guest export is always synchronous; only the host wrapper is async.
+    let mut guest = input.clone();
+    guest.sig.asyncness = None;
+
+    let source = source_file(&input.sig.ident)?;
+    let guest_crate = guest::GuestCrate::locate(
+        Path::new(&cargo_dir),
+        source.as_deref(),
+        &input.sig.ident.unraw().to_string(),
+        &input.sig.to_token_stream().to_string(),
+        input.sig.ident.span(),
+    )?;
+    let wit = create_wit_bindings(&ctx, std::slice::from_ref(&guest))?;
+    // The guest source keeps the line numbers of the original function, so naming it after
+    // the original file makes panic locations and debug info point at the user's code.
+    let component = guest_crate.component(
+        &create_component_source(vec![], vec![guest]),
+        &wit,
+        types.as_deref(),
+        source.as_deref(),
+        options.debug,
+    )?;
+
+    let impls = value_impls(&ctx);
+    let statics = module_statics(&component, !is_async, is_async);
+    let wrapper = host_wrapper(&ctx, input.sig, is_async, &statics)?;
+    Ok(quote! {
+        #(#impls)*
+
+        #wrapper
+    })
+}
+
+/// Offloads every `pub fn` of an inline module into a single component.
+///
+/// The rest of the module, such as private helpers and constants, is compiled into the guest
+/// as well and has to be self-contained. Structs are translated to WIT records like those of a
+/// `types` file. On the host, the module keeps its type definitions, `use` items and public
+/// constants, and its public functions are replaced by wrappers that call the component.
+/// Other items, such as `impl` blocks, are rejected, as the host would lose them. Takes the
+/// same options as `#[offload]`.
+#[proc_macro_attribute]
+pub fn offload_module(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let options = match OffloadOptions::parse(attr) {
+        Ok(options) => options,
+        Err(e) => return e.into_compile_error().into(),
+    };
+    let module = syn::parse_macro_input!(item as ItemMod);
+    expand_offload_module(&options, &module)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_offload_module(
+    options: &OffloadOptions,
+    module: &ItemMod,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let mut ctx = TypeContext::default();
+    let cargo_dir = cargo_dir()?;
+    let types = load_types(&mut ctx, &cargo_dir, options)?;
+
+    let Some((_, items)) = &module.content else {
+        return Err(syn::Error::new_spanned(
+            module,
+            "`#[offload_module]` needs an inline module",
+        ));
+    };
+
+    let mut guest_items = vec![];
+    let mut host_items = vec![];
+    let mut exports = vec![];
+    for item in items {
+        match item {
+            syn::Item::Fn(function) if matches!(function.vis, Visibility::Public(_)) => {
+                let mut guest = function.clone();
+                guest.sig.asyncness = None;
+                exports.push((function.sig.clone(), guest.clone()));
+                guest_items.push(syn::Item::Fn(guest));
+            }
+            // The guest gets structs from its bindings, as they are passed through WIT.
+            syn::Item::Struct(item_struct) => {
+                ctx.add_struct_type(item_struct.clone())?;
+                host_items.push(quote! { #item });
+            }
+            syn::Item::Enum(syn::ItemEnum { ident, .. })
+            | syn::Item::Type(syn::ItemType { ident, .. }) => {
+                let kind = match item {
+                    syn::Item::Enum(_) => "enums",
+                    _ => "type aliases",
+                };
+                ctx.untranslated.insert(ident.to_string(), kind);
+                host_items.push(quote! { #item });
+                guest_items.push(item.clone());
+            }
+            syn::Item::Use(_) => {
+                host_items.push(quote! {
+                    #[allow(unused_imports)]
+                    #item
+                });
+                guest_items.push(item.clone());
+            }
+            syn::Item::Const(syn::ItemConst {
+                vis: Visibility::Public(_),
+                ..
+            })
+            | syn::Item::Static(syn::ItemStatic {
+                vis: Visibility::Public(_),
+                ..
+            }) => {
+                host_items.push(quote! { #item });
+                guest_items.push(item.clone());
+            }
+            // Private helpers are only used by the guest.
+            syn::Item::Fn(_) | syn::Item::Const(_) | syn::Item::Static(_) => {
+                guest_items.push(item.clone());
+            }
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    item,
+                    "`#[offload_module]` only supports functions, structs, enums, type \
+                     aliases, `use` items, constants and statics",
+                ))
+            }
+        }
+    }
+
+    let guest_exports: Vec<_> = exports.iter().map(|(_, guest)| guest.clone()).collect();
+    // Also rejects methods, which the forwards below can't call.
+    let wit = create_wit_bindings(&ctx, &guest_exports)?;
+    // The functions stay at the top level of the guest, where helpers can call them, and the
+    // `Guest` implementation forwards to them.
+    let forwards = exports.iter().map(|(sig, _)| {
+        let name = &sig.ident;
+        let (args, tys): (Vec<_>, Vec<_>) = sig
+            .inputs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, arg)| match arg {
+                syn::FnArg::Receiver(_) => None,
+                syn::FnArg::Typed(t) => Some((format_ident!("arg{i}"), &t.ty)),
+            })
+            .unzip();
+        let output = &sig.output;
+        syn::parse_quote! {
+            fn #name(#(#args: #tys),*) #output {
+                #name(#(#args),*)
+            }
+        }
+    });
+    let forwards = forwards.collect();
+    let signature = exports
+        .iter()
+        .map(|(sig, _)| sig.to_token_stream().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let source = source_file(&module.ident)?;
+    let guest_crate = guest::GuestCrate::locate(
+        Path::new(&cargo_dir),
+        source.as_deref(),
+        &module.ident.unraw().to_string(),
+        &signature,
+        module.ident.span(),
+    )?;
+    let component = guest_crate.component(
+        &create_component_source(guest_items, forwards),
+        &wit,
+        types.as_deref(),
+        source.as_deref(),
+        options.debug,
+    )?;
+
+    let impls = value_impls(&ctx);
+    let is_async = |sig: &Signature| options.is_async || sig.asyncness.is_some();
+    let statics = module_statics(
+        &component,
+        exports.iter().any(|(sig, _)| !is_async(sig)),
+        exports.iter().any(|(sig, _)| is_async(sig)),
+    );
+    let wrappers = exports
+        .into_iter()
+        .map(|(sig, _)| {
+            let is_async = is_async(&sig);
+            host_wrapper(&ctx, sig, is_async, &quote! {})
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+    let ItemMod {
+        attrs, vis, ident, ..
+    } = module;
+    Ok(quote! {
+        #(#attrs)*
+        #vis mod #ident {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #(#host_items)*
+            #(#impls)*
+
+            #statics
+
+            #(#wrappers)*
+        }
+    })
+}
+
+/// Implements `From<T> for wasm_offload::Val`, mapping structs to records or tuples and enums to
+/// enums or variants. Field and case names are converted to kebab-case as in WIT.
+#[proc_macro_derive(IntoVal)]
+pub fn derive_into_val(item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as DeriveInput);
+    derive::derive_into_val(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Implements `wasm_offload::FromVal`, the inverse of `IntoVal`.
+#[proc_macro_derive(FromVal)]
+pub fn derive_from_val(item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as DeriveInput);
+    derive::derive_from_val(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
 
-    TokenStream::from(output)
+/// Implements `wasm_offload::HasValType` with the type that `IntoVal` produces.
+#[proc_macro_derive(HasValType)]
+pub fn derive_has_val_type(item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as DeriveInput);
+    derive::derive_has_val_type(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
 }
@@ -0,0 +1,20 @@
+use wasm_offload::offload;
+use wasm_offload_wasmtime::init_offload;
+
+init_offload!(async);
+
+#[offload(async)]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[offload]
+async fn mul(a: i32, b: i32) -> i32 {
+    a * b
+}
+
+#[tokio::main]
+async fn main() {
+    let (sum, product) = tokio::join!(add(1, 2), mul(3, 4));
+    println!("{sum:?} {product:?}");
+}
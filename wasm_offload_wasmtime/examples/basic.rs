@@ -2,11 +2,9 @@ use std::sync::Arc;
 use std::sync::LazyLock;
 use std::sync::Mutex;
 use wasm_offload::offload;
-use wasm_offload::OffloadTarget;
-use wasm_offload::Val;
-use wasm_offload_wasmtime::{init_offload, WasmtimeOffload};
+use wasm_offload_wasmtime::{init_offload, Stdio, WasiConfig, WasmtimeOffload};
 
-init_offload!();
+init_offload!(builder = WasmtimeOffload::builder().wasi(WasiConfig::new().stdio(Stdio::Inherit)));
 
 #[offload]
 fn add(a: i32, b: i32) -> i32 {
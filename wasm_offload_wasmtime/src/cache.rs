@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+use wasm_offload::ModuleHandle;
+use wasmtime::component::InstancePre;
+
+use crate::{OffloaderState, WasmtimeOffloadError};
+
+/// A compiled component, pre-instantiated against the linker.
+#[derive(Clone)]
+pub(crate) struct Module {
+    pub(crate) pre: InstancePre<OffloaderState>,
+    /// The number of core instances an instance of the component consists of, see
+    /// [`limits::core_instances`](crate::limits::core_instances).
+    pub(crate) core_instances: usize,
+}
+
+/// Compiled components, deduplicated by the SHA-256 hash of their bytes.
+///
+/// Components are kept pre-instantiated against the linker, so creating an instance for a
+/// call neither compiles nor resolves imports.
+#[derive(Default)]
+pub(crate) struct ModuleCache {
+    by_hash: HashMap<[u8; 32], ModuleHandle>,
+    modules: Vec<Module>,
+}
+
+impl ModuleCache {
+    pub(crate) fn hash(module: &[u8]) -> [u8; 32] {
+        Sha256::digest(module).into()
+    }
+
+    pub(crate) fn lookup(&self, hash: &[u8; 32]) -> Option<ModuleHandle> {
+        self.by_hash.get(hash).copied()
+    }
+
+    pub(crate) fn insert(&mut self, hash: [u8; 32], module: Module) -> ModuleHandle {
+        *self.by_hash.entry(hash).or_insert_with(|| {
+            self.modules.push(module);
+            ModuleHandle::new(self.modules.len() as u64 - 1)
+        })
+    }
+
+    pub(crate) fn get(&self, handle: ModuleHandle) -> Result<&Module, WasmtimeOffloadError> {
+        self.modules
+            .get(handle.id() as usize)
+            .ok_or(WasmtimeOffloadError::UnknownModule(handle))
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    sync::{atomic::AtomicUsize, Mutex, RwLock},
+    time::Duration,
+};
+
+use wasmtime::{
+    component::Linker, Config, Engine, InstanceAllocationStrategy, OptLevel,
+    PoolingAllocationConfig, ProfilingStrategy, StoreContextMut, Strategy, WasmBacktraceDetails,
+};
+
+use crate::{
+    cache::ModuleCache,
+    deadline::Ticker,
+    limits::Limits,
+    profile::{ProfileOutput, Profiler},
+    runtime::{Instances, Runtime},
+    wasi::WasiConfig,
+    AsyncWasmtimeOffload, OffloaderState, WasmtimeOffload, WasmtimeOffloadError,
+    WasmtimeOffloadPool,
+};
+
+/// Upper bound for the core instances, memories and tables of a single offloaded component,
+/// used to size the pooling allocator.
+const POOLED_ITEMS_PER_COMPONENT: u32 = 16;
+
+/// How instances of a loaded module are created and kept between calls.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InstancePolicy {
+    /// Every call instantiates the module in a new store, which is dropped afterwards. Calls
+    /// never observe each other's guest state.
+    #[default]
+    FreshPerCall,
+    /// Each module is instantiated once and the instance is kept for later calls, so guest
+    /// state such as globals and heap allocations persists between them.
+    Reuse,
+    /// Like [`InstancePolicy::FreshPerCall`], every call instantiates the module in a new
+    /// store. The instances, memories and tables of the store are taken from pre-reserved pool
+    /// slots, which are reset and recycled once the call finishes. This makes instantiation
+    /// cheaper and puts a hard bound on the memory used by instances.
+    ///
+    /// At most `max` calls can have an instance at the same time, each using up to 16 core
+    /// instances, memories and tables. Calls don't wait for a slot: one that finds the pool
+    /// exhausted fails with [`WasmtimeOffloadError::PoolExhausted`]. This only happens when
+    /// calls run concurrently, i.e. with more workers in a [`WasmtimeOffloadPool`] or more
+    /// calls in flight on an [`AsyncWasmtimeOffload`] than `max`.
+    Pooled { max: u32 },
+}
+
+/// A WebAssembly proposal that can be turned on or off with
+/// [`WasmtimeOffloadBuilder::wasm_feature`]. Features not listed here keep wasmtime's defaults.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WasmFeature {
+    Simd,
+    RelaxedSimd,
+    BulkMemory,
+    MultiValue,
+    MultiMemory,
+    Memory64,
+    TailCall,
+    ExtendedConst,
+}
+
+/// Options for a single call, see e.g. [`WasmtimeOffload::call_with_options`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CallOptions {
+    pub(crate) deadline: Option<Duration>,
+}
+
+impl CallOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Interrupts the call once it has run for `timeout`, failing it with
+    /// [`WasmtimeOffloadError::Timeout`]. Deadlines are checked about every 10ms.
+    ///
+    /// Synchronous offloaders only support deadlines if they are built with
+    /// [`WasmtimeOffloadBuilder::deadlines`].
+    pub fn deadline(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(timeout);
+        self
+    }
+}
+
+/// Configures and creates a [`WasmtimeOffload`], [`WasmtimeOffloadPool`] or
+/// [`AsyncWasmtimeOffload`].
+#[derive(Clone, Debug, Default)]
+pub struct WasmtimeOffloadBuilder {
+    instance_policy: InstancePolicy,
+    limits: Limits,
+    deadlines: bool,
+    strategy: Option<Strategy>,
+    opt_level: Option<OptLevel>,
+    features: Vec<(WasmFeature, bool)>,
+    debug_info: bool,
+    profiling_strategy: Option<ProfilingStrategy>,
+    guest_profiling: Option<ProfileOutput>,
+    wasi: WasiConfig,
+}
+
+impl WasmtimeOffloadBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn instance_policy(mut self, policy: InstancePolicy) -> Self {
+        self.instance_policy = policy;
+        self
+    }
+
+    /// Meters guest execution and gives every call a budget of `fuel` units, roughly one per
+    /// executed wasm instruction. A call that uses up its budget fails with
+    /// [`WasmtimeOffloadError::OutOfFuel`].
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.limits.fuel = Some(fuel);
+        self
+    }
+
+    /// Caps the size of each linear memory at `bytes`. Growing a memory beyond it fails with
+    /// [`WasmtimeOffloadError::MemoryLimitExceeded`].
+    pub fn max_memory_size(mut self, bytes: usize) -> Self {
+        self.limits.memory_size = Some(bytes);
+        self
+    }
+
+    /// Caps the number of elements in each table. Growing a table beyond it fails with
+    /// [`WasmtimeOffloadError::LimitExceeded`].
+    pub fn max_table_elements(mut self, elements: usize) -> Self {
+        self.limits.table_elements = Some(elements);
+        self
+    }
+
+    /// Caps the number of core instances per store; a component usually consists of several.
+    /// Instantiating more fails with [`WasmtimeOffloadError::LimitExceeded`].
+    pub fn max_instances(mut self, count: usize) -> Self {
+        self.limits.instances = Some(count);
+        self
+    }
+
+    /// Allows calls to be given a [deadline](CallOptions::deadline). Guest code then checks
+    /// for it periodically, which costs a little performance, and a thread is kept running
+    /// that advances the clock deadlines are measured with. Async offloaders always support
+    /// deadlines, as they need the same checks to yield to the executor.
+    pub fn deadlines(mut self, enabled: bool) -> Self {
+        self.deadlines = enabled;
+        self
+    }
+
+    /// Selects the compiler used to translate guest code; wasmtime picks one by default.
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = Some(strategy);
+        self
+    }
+
+    /// Sets how much effort the compiler spends on optimizing guest code.
+    pub fn opt_level(mut self, level: OptLevel) -> Self {
+        self.opt_level = Some(level);
+        self
+    }
+
+    pub fn wasm_feature(mut self, feature: WasmFeature, enabled: bool) -> Self {
+        self.features.push((feature, enabled));
+        self
+    }
+
+    /// Uses the debug info of guests built with `#[offload(debug)]`. Wasm backtraces in
+    /// [`WasmtimeOffloadError::Trap`] then show the file and line of each frame in the
+    /// original source, and native debuggers can step through guest code.
+    pub fn debug_info(mut self, enabled: bool) -> Self {
+        self.debug_info = enabled;
+        self
+    }
+
+    /// Makes guest code visible to native profilers, e.g. through a perf map or jitdump file
+    /// for `perf`.
+    pub fn profiling_strategy(mut self, strategy: ProfilingStrategy) -> Self {
+        self.profiling_strategy = Some(strategy);
+        self
+    }
+
+    /// Samples the stack of the guest on every epoch tick, about every 10ms, and writes the
+    /// samples to `output` after each call. Calls that are shorter than a tick may not show up.
+    ///
+    /// Profile files are rewritten after every call, which gets slower as they grow, so this is
+    /// meant for finding hot spots rather than for production use.
+    pub fn guest_profiling(mut self, output: ProfileOutput) -> Self {
+        self.guest_profiling = Some(output);
+        self
+    }
+
+    /// Sets the WASI environment every call runs in.
+    pub fn wasi(mut self, wasi: WasiConfig) -> Self {
+        self.wasi = wasi;
+        self
+    }
+
+    /// Whether guests are interrupted on epoch ticks, which deadlines, guest profiling and
+    /// yielding to an async executor rely on.
+    fn epochs(&self, async_support: bool) -> bool {
+        self.deadlines || self.guest_profiling.is_some() || async_support
+    }
+
+    fn config(&self, async_support: bool) -> Config {
+        let mut config = Config::new();
+        if let Some(strategy) = self.strategy {
+            config.strategy(strategy);
+        }
+        if let Some(level) = self.opt_level {
+            config.cranelift_opt_level(level);
+        }
+        for &(feature, enabled) in &self.features {
+            match feature {
+                WasmFeature::Simd => config.wasm_simd(enabled),
+                WasmFeature::RelaxedSimd => config.wasm_relaxed_simd(enabled),
+                WasmFeature::BulkMemory => config.wasm_bulk_memory(enabled),
+                WasmFeature::MultiValue => config.wasm_multi_value(enabled),
+                WasmFeature::MultiMemory => config.wasm_multi_memory(enabled),
+                WasmFeature::Memory64 => config.wasm_memory64(enabled),
+                WasmFeature::TailCall => config.wasm_tail_call(enabled),
+                WasmFeature::ExtendedConst => config.wasm_extended_const(enabled),
+            };
+        }
+        config.async_support(async_support);
+        config.consume_fuel(self.limits.fuel.is_some());
+        config.epoch_interruption(self.epochs(async_support));
+        if self.debug_info {
+            config
+                .debug_info(true)
+                .wasm_backtrace_details(WasmBacktraceDetails::Enable);
+        }
+        if let Some(strategy) = self.profiling_strategy {
+            config.profiler(strategy);
+        }
+        if let InstancePolicy::Pooled { max } = self.instance_policy {
+            let per_component = max.saturating_mul(POOLED_ITEMS_PER_COMPONENT);
+            let mut pooling = PoolingAllocationConfig::default();
+            pooling
+                .total_component_instances(max)
+                .total_core_instances(per_component)
+                .total_memories(per_component)
+                .total_tables(per_component);
+            config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
+        }
+        config
+    }
+
+    fn runtime(&self, async_support: bool) -> Result<Runtime, WasmtimeOffloadError> {
+        // Surface unusable WASI settings, such as a missing preopened directory, right away
+        // instead of on the first call.
+        self.wasi.build()?;
+        let engine = Engine::new(&self.config(async_support))?;
+        let ticker = self
+            .epochs(async_support)
+            .then(|| Ticker::start(&engine))
+            .transpose()
+            .map_err(wasmtime::Error::from)?;
+        let mut linker = Linker::<OffloaderState>::new(&engine);
+        if async_support {
+            wasmtime_wasi::add_to_linker_async(&mut linker)?;
+        } else {
+            wasmtime_wasi::add_to_linker_sync(&mut linker)?;
+        }
+        linker.root().func_wrap(
+            "report-panic",
+            |mut store: StoreContextMut<'_, OffloaderState>,
+             (message, file, line): (String, String, u32)| {
+                store.data_mut().panic = Some(WasmtimeOffloadError::GuestPanic {
+                    message,
+                    file,
+                    line,
+                });
+                Ok(())
+            },
+        )?;
+        Ok(Runtime {
+            engine,
+            linker,
+            policy: self.instance_policy,
+            limits: self.limits,
+            wasi: self.wasi.clone(),
+            profiler: self.guest_profiling.clone().map(Profiler::new),
+            yields: async_support,
+            ticker,
+        })
+    }
+
+    pub fn build(self) -> Result<WasmtimeOffload, WasmtimeOffloadError> {
+        Ok(WasmtimeOffload {
+            runtime: self.runtime(false)?,
+            modules: ModuleCache::default(),
+            instances: Instances::default(),
+        })
+    }
+
+    /// Builds a pool that runs up to `workers` calls in parallel.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn build_pool(self, workers: usize) -> Result<WasmtimeOffloadPool, WasmtimeOffloadError> {
+        assert!(workers > 0, "a pool needs at least one worker");
+        Ok(WasmtimeOffloadPool {
+            runtime: self.runtime(false)?,
+            modules: RwLock::new(ModuleCache::default()),
+            workers: (0..workers)
+                .map(|_| Mutex::new(Instances::default()))
+                .collect(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Builds an async offloader. [`InstancePolicy::Reuse`] is not supported, as a shared
+    /// instance could only serve one task at a time.
+    pub fn build_async(self) -> Result<AsyncWasmtimeOffload, WasmtimeOffloadError> {
+        if self.instance_policy == InstancePolicy::Reuse {
+            return Err(WasmtimeOffloadError::UnsupportedPolicy(
+                self.instance_policy,
+            ));
+        }
+        Ok(AsyncWasmtimeOffload {
+            runtime: self.runtime(true)?,
+            modules: Mutex::new(ModuleCache::default()),
+        })
+    }
+}
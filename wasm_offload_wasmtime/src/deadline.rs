@@ -0,0 +1,111 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use wasmtime::{Engine, UpdateDeadline};
+
+use crate::WasmtimeOffloadError;
+
+/// How often the epoch of an engine is incremented, which is also the granularity at which
+/// deadlines are enforced and guests are sampled while being profiled.
+pub(crate) const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Deadline used for calls without a timeout, far enough away that adding it to the current
+/// epoch can't overflow.
+const UNBOUNDED_TICKS: u64 = u64::MAX / 2;
+
+/// A thread that increments the epoch of an engine every [`EPOCH_TICK`]. The thread is
+/// stopped when the ticker is dropped.
+pub(crate) struct Ticker {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Ticker {
+    pub(crate) fn start(engine: &Engine) -> std::io::Result<Self> {
+        let engine = engine.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new()
+            .name("wasm-offload-epoch".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        engine.increment_epoch();
+                        thread::park_timeout(EPOCH_TICK);
+                    }
+                }
+            })?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The point in time at which a running call is interrupted.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline {
+    start: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    pub(crate) fn after(timeout: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            timeout,
+        }
+    }
+}
+
+/// The number of epoch ticks until `deadline` expires. Guests that are being profiled or that
+/// yield to an async executor are interrupted on `every_tick` instead.
+pub(crate) fn ticks(deadline: Option<Deadline>, every_tick: bool) -> u64 {
+    match deadline {
+        _ if every_tick => 1,
+        Some(deadline) => {
+            let remaining = deadline.timeout.saturating_sub(deadline.start.elapsed());
+            remaining.as_nanos().div_ceil(EPOCH_TICK.as_nanos()).max(1) as u64
+        }
+        None => UNBOUNDED_TICKS,
+    }
+}
+
+/// Called by wasmtime when the epoch deadline of a store is reached. The ticker thread may run
+/// late, so this only interrupts the guest once the deadline has actually passed. Async
+/// stores `yield` to the executor on every tick, so that a long-running guest doesn't keep
+/// other tasks from running.
+pub(crate) fn on_epoch(
+    deadline: Option<Deadline>,
+    sampling: bool,
+    yields: bool,
+) -> wasmtime::Result<UpdateDeadline> {
+    if let Some(deadline) = deadline {
+        let elapsed = deadline.start.elapsed();
+        if elapsed >= deadline.timeout {
+            return Err(WasmtimeOffloadError::Timeout { elapsed }.into());
+        }
+    }
+    let ticks = ticks(deadline, sampling || yields);
+    Ok(if yields {
+        UpdateDeadline::Yield(ticks)
+    } else {
+        UpdateDeadline::Continue(ticks)
+    })
+}
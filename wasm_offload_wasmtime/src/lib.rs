@@ -1,19 +1,73 @@
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use cache::ModuleCache;
+use deadline::Deadline;
+use limits::{Limiter, Limits};
+use output::OutputPipes;
+use profile::Sample;
+use runtime::{Call, Instances, Runtime};
 use thiserror::Error;
-use wasm_offload::{OffloadTarget, Val};
-use wasmtime::{component::Component, component::Linker, AsContextMut, Engine, Store};
-use wasmtime_wasi::{ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
+use wasm_offload::{
+    AsyncOffloadTarget, ModuleHandle, OffloadError, OffloadTarget, TypeMismatch, Val,
+};
+use wasmtime::{component::Component, WasmBacktrace};
+use wasmtime_wasi::{ResourceTable, WasiCtx, WasiView};
+
+mod cache;
+mod config;
+mod deadline;
+mod limits;
+mod output;
+mod pool;
+mod profile;
+mod runtime;
+mod types;
+mod wasi;
+
+pub use config::{CallOptions, InstancePolicy, WasmFeature, WasmtimeOffloadBuilder};
+pub use output::CallOutcome;
+pub use pool::WasmtimeOffloadPool;
+pub use profile::ProfileOutput;
+pub use wasi::{DirAccess, Stdio, WasiConfig};
+pub use wasmtime::{OptLevel, ProfilingStrategy, Strategy};
 
 #[macro_export]
 macro_rules! init_offload {
     () => {
         static OFFLOADER: std::sync::LazyLock<
-            std::sync::Arc<std::sync::Mutex<wasm_offload_wasmtime::WasmtimeOffload>>,
+            std::sync::Mutex<wasm_offload_wasmtime::WasmtimeOffload>,
         > = std::sync::LazyLock::new(|| {
-            std::sync::Arc::new(std::sync::Mutex::new(
-                wasm_offload_wasmtime::WasmtimeOffload::new().unwrap(),
-            ))
+            std::sync::Mutex::new(wasm_offload_wasmtime::WasmtimeOffload::new().unwrap())
         });
     };
+    (pool = $workers:expr) => {
+        static OFFLOADER: std::sync::LazyLock<wasm_offload_wasmtime::WasmtimeOffloadPool> =
+            std::sync::LazyLock::new(|| {
+                wasm_offload_wasmtime::WasmtimeOffloadPool::new($workers).unwrap()
+            });
+    };
+    (async) => {
+        static ASYNC_OFFLOADER: std::sync::LazyLock<wasm_offload_wasmtime::AsyncWasmtimeOffload> =
+            std::sync::LazyLock::new(|| {
+                wasm_offload_wasmtime::AsyncWasmtimeOffload::new().unwrap()
+            });
+    };
+    (builder = $builder:expr, pool = $workers:expr) => {
+        static OFFLOADER: std::sync::LazyLock<wasm_offload_wasmtime::WasmtimeOffloadPool> =
+            std::sync::LazyLock::new(|| $builder.build_pool($workers).unwrap());
+    };
+    (builder = $builder:expr) => {
+        static OFFLOADER: std::sync::LazyLock<
+            std::sync::Mutex<wasm_offload_wasmtime::WasmtimeOffload>,
+        > = std::sync::LazyLock::new(|| std::sync::Mutex::new($builder.build().unwrap()));
+    };
+    (async, builder = $builder:expr) => {
+        static ASYNC_OFFLOADER: std::sync::LazyLock<wasm_offload_wasmtime::AsyncWasmtimeOffload> =
+            std::sync::LazyLock::new(|| $builder.build_async().unwrap());
+    };
 }
 
 trait WtValExt {
@@ -59,7 +113,9 @@ impl WtValExt for wasmtime::component::Val {
                 Err(e) => Err(e.as_ref().map(|v| Box::new(v.to_offload()))),
             }),
             wasmtime::component::Val::Flags(v) => Val::Flags(v.clone()),
-            wasmtime::component::Val::Resource(_) => unimplemented!(),
+            wasmtime::component::Val::Resource(_) => {
+                unreachable!("exports that return resources are rejected before the call")
+            }
         }
     }
 }
@@ -114,13 +170,202 @@ impl ValExt for Val {
 
 #[derive(Error, Debug)]
 pub enum WasmtimeOffloadError {
-    #[error("wasmtime error")]
-    Wasmtime(#[from] wasmtime::Error),
+    #[error("wasmtime error: {0:#}")]
+    Wasmtime(wasmtime::Error),
+    #[error("failed to compile module: {0:#}")]
+    Compilation(wasmtime::Error),
+    #[error("failed to instantiate module: {0:#}")]
+    Instantiation(wasmtime::Error),
+    #[error("module has no exported function `{name}`, available are: {}", available.join(", "))]
+    ExportNotFound {
+        name: String,
+        available: Vec<String>,
+    },
+    #[error("`{function}` takes {expected} arguments, but {found} were given")]
+    ArgumentCount {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("argument {param} of `{function}` has the wrong type: {mismatch}")]
+    TypeMismatch {
+        function: String,
+        param: usize,
+        mismatch: TypeMismatch,
+    },
+    /// The result of the function contains a resource handle, which can't be passed as a
+    /// [`Val`].
+    #[error("`{function}` returns a resource, which can't be passed to the host")]
+    UnsupportedResult { function: String },
+    #[error("guest panicked at {file}:{line}: {message}")]
+    GuestPanic {
+        message: String,
+        file: String,
+        line: u32,
+    },
+    #[error("`{function}` trapped: {code}{}", fmt_backtrace(wasm_backtrace))]
+    Trap {
+        code: wasmtime::Trap,
+        wasm_backtrace: Option<WasmBacktrace>,
+        function: String,
+    },
+    #[error("guest ran out of fuel")]
+    OutOfFuel,
+    #[error("guest tried to grow a memory to {requested} bytes, but the limit is {limit} bytes")]
+    MemoryLimitExceeded { requested: usize, limit: usize },
+    #[error("guest exceeded the {resource} limit")]
+    LimitExceeded { resource: &'static str },
+    /// All slots of an [`InstancePolicy::Pooled`] offloader are in use by other calls.
+    #[error("instance pool is exhausted: {0}")]
+    PoolExhausted(wasmtime::PoolConcurrencyLimitError),
+    #[error("call timed out after {elapsed:?}")]
+    Timeout { elapsed: Duration },
+    /// A call was given a deadline, but the offloader was built without
+    /// [`WasmtimeOffloadBuilder::deadlines`].
+    #[error("deadlines are not enabled for this offloader")]
+    DeadlinesDisabled,
+    #[error("module handle {0:?} was not loaded by this offloader")]
+    UnknownModule(ModuleHandle),
+    #[error("instance policy {0:?} is not supported by this offloader")]
+    UnsupportedPolicy(InstancePolicy),
+}
+
+impl From<wasmtime::Error> for WasmtimeOffloadError {
+    fn from(err: wasmtime::Error) -> Self {
+        let err = match err.downcast::<WasmtimeOffloadError>() {
+            // Raised by our `ResourceLimiter` or epoch deadline callback.
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        if err.downcast_ref::<wasmtime::Trap>() == Some(&wasmtime::Trap::OutOfFuel) {
+            return WasmtimeOffloadError::OutOfFuel;
+        }
+        let err = match err.downcast::<wasmtime::PoolConcurrencyLimitError>() {
+            Ok(err) => return WasmtimeOffloadError::PoolExhausted(err),
+            Err(err) => err,
+        };
+        WasmtimeOffloadError::Wasmtime(err)
+    }
+}
+
+impl WasmtimeOffloadError {
+    fn instance_limit() -> Self {
+        WasmtimeOffloadError::LimitExceeded {
+            resource: "instance",
+        }
+    }
+
+    fn instantiation(err: wasmtime::Error) -> Self {
+        match Self::from(err) {
+            WasmtimeOffloadError::Wasmtime(err) => WasmtimeOffloadError::Instantiation(err),
+            err => err,
+        }
+    }
+
+    /// Classifies an error raised while `function` was running.
+    fn call(err: wasmtime::Error, function: &str) -> Self {
+        match Self::from(err) {
+            WasmtimeOffloadError::Wasmtime(err) => match err.downcast_ref::<wasmtime::Trap>() {
+                Some(&code) => WasmtimeOffloadError::Trap {
+                    code,
+                    wasm_backtrace: err.downcast::<WasmBacktrace>().ok(),
+                    function: function.to_string(),
+                },
+                None => WasmtimeOffloadError::Wasmtime(err),
+            },
+            err => err,
+        }
+    }
+}
+
+fn fmt_backtrace(backtrace: &Option<WasmBacktrace>) -> String {
+    match backtrace {
+        Some(backtrace) => format!("\n{backtrace}"),
+        None => String::new(),
+    }
+}
+
+impl From<WasmtimeOffloadError> for OffloadError {
+    fn from(err: WasmtimeOffloadError) -> Self {
+        match err {
+            WasmtimeOffloadError::GuestPanic {
+                message,
+                file,
+                line,
+            } => OffloadError::GuestPanic {
+                message,
+                file,
+                line,
+            },
+            WasmtimeOffloadError::ArgumentCount {
+                function,
+                expected,
+                found,
+            } => OffloadError::ArgumentCount {
+                function,
+                expected,
+                found,
+            },
+            // Component function types don't carry parameter names.
+            WasmtimeOffloadError::TypeMismatch {
+                function,
+                param,
+                mismatch,
+            } => OffloadError::ArgumentType {
+                function,
+                param: param.to_string(),
+                mismatch,
+            },
+            err => OffloadError::target(err),
+        }
+    }
 }
 
 pub struct OffloaderState {
     ctx: WasiCtx,
     table: ResourceTable,
+    output: Option<OutputPipes>,
+    limiter: Limiter,
+    deadline: Option<Deadline>,
+    /// The panic the guest reported through `report-panic` during the current call.
+    panic: Option<WasmtimeOffloadError>,
+    /// The stacks sampled during the current call, if guests are profiled.
+    samples: Option<Vec<Sample>>,
+}
+
+impl OffloaderState {
+    fn new(ctx: WasiCtx, output: Option<OutputPipes>, limits: Limits) -> Self {
+        Self {
+            ctx,
+            table: ResourceTable::new(),
+            output,
+            limiter: Limiter(limits),
+            deadline: None,
+            panic: None,
+            samples: None,
+        }
+    }
+
+    /// Classifies an error raised while `function` was running. A guest that panicked has
+    /// reported the panic before trapping, which tells more than the trap itself.
+    fn call_error(&mut self, err: wasmtime::Error, function: &str) -> WasmtimeOffloadError {
+        self.panic
+            .take()
+            .unwrap_or_else(|| WasmtimeOffloadError::call(err, function))
+    }
+
+    fn begin_output(&self, function: &str) {
+        if let Some(output) = &self.output {
+            output.begin(function);
+        }
+    }
+
+    fn finish_output(&self) -> (Vec<u8>, Vec<u8>) {
+        self.output
+            .as_ref()
+            .map(OutputPipes::finish)
+            .unwrap_or_default()
+    }
 }
 
 impl WasiView for OffloaderState {
@@ -134,33 +379,38 @@ impl WasiView for OffloaderState {
 }
 
 pub struct WasmtimeOffload {
-    engine: Engine,
-    store: Store<OffloaderState>,
-    linker: Linker<OffloaderState>,
+    runtime: Runtime,
+    modules: ModuleCache,
+    instances: Instances,
 }
 
 impl WasmtimeOffload {
     pub fn new() -> Result<Self, WasmtimeOffloadError> {
-        let engine = Engine::default();
-        let mut linker = Linker::<OffloaderState>::new(&engine);
-        wasmtime_wasi::add_to_linker_sync(&mut linker)?;
-        // preview1::add_to_linker_sync(&mut linker, |t| t);
-
-        let mut builder = WasiCtxBuilder::new();
-
-        let store = Store::new(
-            &engine,
-            OffloaderState {
-                ctx: builder.build(),
-                table: ResourceTable::new(),
-            },
-        );
+        WasmtimeOffloadBuilder::new().build()
+    }
 
-        Ok(Self {
-            engine,
-            store,
-            linker,
-        })
+    pub fn builder() -> WasmtimeOffloadBuilder {
+        WasmtimeOffloadBuilder::new()
+    }
+
+    /// Like [`OffloadTarget::call`], with per-call `options` such as a deadline. The outcome
+    /// also holds the output of the guest if it is captured.
+    pub fn call_with_options(
+        &mut self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
+        options: &CallOptions,
+    ) -> Result<CallOutcome, WasmtimeOffloadError> {
+        let compiled = self.modules.get(module)?;
+        let call = Call {
+            name,
+            args,
+            returns,
+            options,
+        };
+        self.instances.call(&self.runtime, compiled, module, call)
     }
 }
 
@@ -171,31 +421,133 @@ impl OffloadTarget for WasmtimeOffload {
         Ok(())
     }
 
-    fn call_function(
+    fn load_module(&mut self, module: &[u8]) -> Result<ModuleHandle, Self::Error> {
+        let hash = ModuleCache::hash(module);
+        if let Some(handle) = self.modules.lookup(&hash) {
+            return Ok(handle);
+        }
+        let module = self.runtime.compile(module)?;
+        Ok(self.modules.insert(hash, module))
+    }
+
+    fn call(
         &mut self,
-        module: &[u8],
+        module: ModuleHandle,
         name: &str,
         args: &[wasm_offload::Val],
         returns: bool,
     ) -> Result<Option<Val>, Self::Error> {
-        // let module = Module::new(&self.engine, module)?;
-        // let mut ctx = self.store.as_context_mut();
-        // let instance = self.linker.instantiate(&mut ctx, &module)?;
-        // let func = instance.get_func(&mut ctx, name).unwrap();
+        self.call_with_options(module, name, args, returns, &CallOptions::default())
+            .map(|outcome| outcome.value)
+    }
+}
+
+/// An offload target for async code, built on wasmtime's async support.
+///
+/// Every call gets its own store, so concurrent calls don't wait on each other. A running
+/// guest yields to the executor on every epoch tick, about every 10ms, so it shares its
+/// thread with other tasks instead of blocking it until the call returns. Modules are compiled
+/// on a blocking thread of the ambient tokio runtime, or of a runtime of wasmtime-wasi's own
+/// outside of tokio. Stores are allocated according to the [`InstancePolicy`] it was built
+/// with.
+pub struct AsyncWasmtimeOffload {
+    runtime: Runtime,
+    modules: Mutex<ModuleCache>,
+}
+
+impl AsyncWasmtimeOffload {
+    pub fn new() -> Result<Self, WasmtimeOffloadError> {
+        WasmtimeOffloadBuilder::new().build_async()
+    }
 
-        let component = Component::new(&self.engine, module)?;
-        let mut ctx = self.store.as_context_mut();
-        let instance = self.linker.instantiate(&mut ctx, &component)?;
-        let func = instance.get_func(&mut ctx, name).unwrap();
+    pub fn builder() -> WasmtimeOffloadBuilder {
+        WasmtimeOffloadBuilder::new()
+    }
+
+    fn modules(&self) -> MutexGuard<'_, ModuleCache> {
+        // The cache is never left half-updated, so a poisoned lock is still usable.
+        self.modules.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Like [`AsyncOffloadTarget::call`], with per-call `options` such as a deadline. The
+    /// outcome also holds the output of the guest if it is captured.
+    pub async fn call_with_options(
+        &self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
+        options: &CallOptions,
+    ) -> Result<CallOutcome, WasmtimeOffloadError> {
+        let module = self.modules().get(module)?.clone();
+        let export = self.runtime.export(&module, name, args)?;
+        self.runtime.check_instances(&module)?;
+        let mut store = self.runtime.new_store()?;
+        let instance = module
+            .pre
+            .instantiate_async(&mut store)
+            .await
+            .map_err(WasmtimeOffloadError::instantiation)?;
+        let func = instance
+            .get_func(&mut store, export)
+            .expect("export was looked up in the instantiated component");
 
-        // let mut output = Vec::with_capacity(1);
         let mut output = if returns {
             vec![wasmtime::component::Val::U32(0)]
         } else {
             vec![]
         };
         let args: Vec<_> = args.iter().map(ValExt::to_wasmtime).collect();
-        func.call(&mut ctx, &args, &mut output)?;
-        Ok(output.get(0).map(WtValExt::to_offload))
+        self.runtime.arm(&mut store, options)?;
+        store.data().begin_output(name);
+        let result = match func.call_async(&mut store, &args, &mut output).await {
+            Ok(()) => func.post_return_async(&mut store).await,
+            Err(err) => Err(err),
+        };
+        let (stdout, stderr) = store.data().finish_output();
+        self.runtime.finish_profile(&mut store, name);
+        result.map_err(|err| store.data_mut().call_error(err, name))?;
+        Ok(CallOutcome {
+            value: output.first().map(WtValExt::to_offload),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+impl AsyncOffloadTarget for AsyncWasmtimeOffload {
+    type Error = WasmtimeOffloadError;
+
+    async fn initialize(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn load_module(&self, module: &[u8]) -> Result<ModuleHandle, Self::Error> {
+        let hash = ModuleCache::hash(module);
+        if let Some(handle) = self.modules().lookup(&hash) {
+            return Ok(handle);
+        }
+        // Compile without holding the lock; a concurrent load of the same bytes compiles twice
+        // but still ends up with a single handle.
+        let engine = self.runtime.engine.clone();
+        let bytes = module.to_vec();
+        let component =
+            wasmtime_wasi::runtime::spawn_blocking(move || Component::new(&engine, bytes))
+                .await
+                .map_err(WasmtimeOffloadError::Compilation)?;
+        let module = self.runtime.link(&component, module)?;
+        Ok(self.modules().insert(hash, module))
+    }
+
+    async fn call(
+        &self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
+    ) -> Result<Option<Val>, Self::Error> {
+        self.call_with_options(module, name, args, returns, &CallOptions::default())
+            .await
+            .map(|outcome| outcome.value)
     }
 }
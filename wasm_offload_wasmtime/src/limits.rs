@@ -0,0 +1,187 @@
+use wasmparser::{
+    ComponentAlias, ComponentExternalKind, ComponentInstance, ComponentOuterAliasKind,
+    ComponentTypeRef, Encoding, Instance, Parser, Payload,
+};
+use wasmtime::{
+    ResourceLimiter, DEFAULT_INSTANCE_LIMIT, DEFAULT_MEMORY_LIMIT, DEFAULT_TABLE_LIMIT,
+};
+
+use crate::WasmtimeOffloadError;
+
+/// Limits applied to every store, configured through
+/// [`WasmtimeOffloadBuilder`](crate::WasmtimeOffloadBuilder).
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Limits {
+    pub(crate) fuel: Option<u64>,
+    pub(crate) memory_size: Option<usize>,
+    pub(crate) table_elements: Option<usize>,
+    pub(crate) instances: Option<usize>,
+}
+
+/// Enforces [`Limits`] on a store. Growth beyond a limit traps with a [`WasmtimeOffloadError`]
+/// describing the breach, which surfaces unchanged from the call.
+pub(crate) struct Limiter(pub(crate) Limits);
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        match self.0.memory_size {
+            Some(limit) if desired > limit => Err(WasmtimeOffloadError::MemoryLimitExceeded {
+                requested: desired,
+                limit,
+            }
+            .into()),
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        match self.0.table_elements {
+            Some(limit) if desired > limit => Err(WasmtimeOffloadError::LimitExceeded {
+                resource: "table element",
+            }
+            .into()),
+            _ => Ok(true),
+        }
+    }
+
+    fn instances(&self) -> usize {
+        self.0.instances.unwrap_or(DEFAULT_INSTANCE_LIMIT)
+    }
+
+    fn tables(&self) -> usize {
+        DEFAULT_TABLE_LIMIT
+    }
+
+    fn memories(&self) -> usize {
+        DEFAULT_MEMORY_LIMIT
+    }
+}
+
+/// The core instances created so far by a component that is being parsed, and the number of
+/// core instances that each component in its index space creates when instantiated.
+#[derive(Default)]
+struct ComponentScope {
+    instances: usize,
+    components: Vec<usize>,
+}
+
+/// The number of core instances that instantiating the component `module` creates, which is
+/// what wasmtime counts against [`Limits::instances`].
+///
+/// Wasmtime only reports a breach of the limit as an untyped instantiation error, so the count
+/// is taken from the component up front. Every core `instantiate` counts once and every
+/// instantiation of a nested component counts the instances of that component. Components
+/// that are imported, or exported by an instance, can't be resolved here and count as none.
+pub(crate) fn core_instances(module: &[u8]) -> wasmtime::Result<usize> {
+    let module = wat::parse_bytes(module)?;
+    // One entry per component or core module the parser is in, `None` for core modules.
+    let mut scopes: Vec<Option<ComponentScope>> = vec![];
+    for payload in Parser::new(0).parse_all(&module) {
+        let payload = payload?;
+        match payload {
+            Payload::Version { encoding, .. } => {
+                scopes.push((encoding == Encoding::Component).then(ComponentScope::default));
+                continue;
+            }
+            Payload::End(_) => {
+                if let Some(Some(scope)) = scopes.pop() {
+                    match scopes.last_mut() {
+                        Some(Some(parent)) => parent.components.push(scope.instances),
+                        _ => return Ok(scope.instances),
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        // Components can only be nested in components, so an outer alias always refers to
+        // a component scope.
+        let outer = |count: u32, index: u32| {
+            let scope = scopes.len().checked_sub(count as usize + 1)?;
+            scopes[scope]
+                .as_ref()?
+                .components
+                .get(index as usize)
+                .copied()
+        };
+        let mut defined = vec![];
+        let mut instances = 0;
+        match payload {
+            Payload::InstanceSection(reader) => {
+                for instance in reader {
+                    if let Instance::Instantiate { .. } = instance? {
+                        instances += 1;
+                    }
+                }
+            }
+            Payload::ComponentInstanceSection(reader) => {
+                let Some(Some(scope)) = scopes.last() else {
+                    continue;
+                };
+                for instance in reader {
+                    if let ComponentInstance::Instantiate {
+                        component_index, ..
+                    } = instance?
+                    {
+                        instances += scope
+                            .components
+                            .get(component_index as usize)
+                            .copied()
+                            .unwrap_or(0);
+                    }
+                }
+            }
+            Payload::ComponentImportSection(reader) => {
+                for import in reader {
+                    if let ComponentTypeRef::Component(_) = import?.ty {
+                        defined.push(0);
+                    }
+                }
+            }
+            Payload::ComponentAliasSection(reader) => {
+                for alias in reader {
+                    match alias? {
+                        ComponentAlias::InstanceExport {
+                            kind: ComponentExternalKind::Component,
+                            ..
+                        } => defined.push(0),
+                        ComponentAlias::Outer {
+                            kind: ComponentOuterAliasKind::Component,
+                            count,
+                            index,
+                        } => defined.push(outer(count, index).unwrap_or(0)),
+                        _ => {}
+                    }
+                }
+            }
+            Payload::ComponentExportSection(reader) => {
+                let Some(Some(scope)) = scopes.last() else {
+                    continue;
+                };
+                for export in reader {
+                    let export = export?;
+                    if export.kind == ComponentExternalKind::Component {
+                        let count = scope.components.get(export.index as usize);
+                        defined.push(count.copied().unwrap_or(0));
+                    }
+                }
+            }
+            _ => {}
+        }
+        if let Some(Some(scope)) = scopes.last_mut() {
+            scope.instances += instances;
+            scope.components.extend(defined);
+        }
+    }
+    Ok(0)
+}
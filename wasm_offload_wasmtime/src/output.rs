@@ -0,0 +1,155 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use bytes::Bytes;
+use wasm_offload::Val;
+use wasmtime_wasi::{HostOutputStream, StdoutStream, StreamResult, Subscribe};
+
+/// The number of bytes a guest may write to a stream at once.
+const WRITE_BUDGET: usize = 64 * 1024;
+
+/// The result of a call together with what the guest wrote to stdout and stderr during it.
+///
+/// The output is only collected under [`Stdio::Capture`](crate::Stdio::Capture) and empty
+/// otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct CallOutcome {
+    pub value: Option<Val>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Sink {
+    Capture,
+    Log(log::Level),
+}
+
+#[derive(Default)]
+struct Buffer {
+    bytes: Vec<u8>,
+    function: String,
+}
+
+/// A stdout or stderr stream that is shared between the guest and the host, so that the host
+/// can pick up the output after each call.
+#[derive(Clone)]
+pub(crate) struct GuestOutput {
+    sink: Sink,
+    buffer: Arc<Mutex<Buffer>>,
+}
+
+impl GuestOutput {
+    fn new(sink: Sink) -> Self {
+        Self {
+            sink,
+            buffer: Arc::default(),
+        }
+    }
+
+    fn buffer(&self) -> std::sync::MutexGuard<'_, Buffer> {
+        self.buffer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn begin(&self, function: &str) {
+        let mut buffer = self.buffer();
+        buffer.bytes.clear();
+        buffer.function = function.to_string();
+    }
+
+    fn finish(&self) -> Vec<u8> {
+        let mut buffer = self.buffer();
+        let bytes = mem::take(&mut buffer.bytes);
+        match self.sink {
+            Sink::Capture => bytes,
+            Sink::Log(level) => {
+                // A last line without a trailing newline.
+                if !bytes.is_empty() {
+                    log_line(level, &buffer.function, &bytes);
+                }
+                Vec::new()
+            }
+        }
+    }
+}
+
+fn log_line(level: log::Level, function: &str, line: &[u8]) {
+    log::log!(
+        target: "wasm_offload_wasmtime::guest",
+        level,
+        "{function}: {}",
+        String::from_utf8_lossy(line)
+    );
+}
+
+impl StdoutStream for GuestOutput {
+    fn stream(&self) -> Box<dyn HostOutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+impl HostOutputStream for GuestOutput {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        let mut buffer = self.buffer();
+        buffer.bytes.extend_from_slice(&bytes);
+        if let Sink::Log(level) = self.sink {
+            while let Some(end) = buffer.bytes.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.bytes.drain(..=end).collect();
+                log_line(level, &buffer.function, &line[..end]);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(WRITE_BUDGET)
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl Subscribe for GuestOutput {
+    async fn ready(&mut self) {}
+}
+
+/// The stdout and stderr of a store whose output is captured or logged.
+pub(crate) struct OutputPipes {
+    pub(crate) stdout: GuestOutput,
+    pub(crate) stderr: GuestOutput,
+}
+
+impl OutputPipes {
+    pub(crate) fn capture() -> Self {
+        Self {
+            stdout: GuestOutput::new(Sink::Capture),
+            stderr: GuestOutput::new(Sink::Capture),
+        }
+    }
+
+    pub(crate) fn log() -> Self {
+        Self {
+            stdout: GuestOutput::new(Sink::Log(log::Level::Info)),
+            stderr: GuestOutput::new(Sink::Log(log::Level::Warn)),
+        }
+    }
+
+    /// Prepares the pipes for a call to `function`.
+    pub(crate) fn begin(&self, function: &str) {
+        self.stdout.begin(function);
+        self.stderr.begin(function);
+    }
+
+    /// Returns the stdout and stderr written since [`OutputPipes::begin`].
+    pub(crate) fn finish(&self) -> (Vec<u8>, Vec<u8>) {
+        (self.stdout.finish(), self.stderr.finish())
+    }
+}
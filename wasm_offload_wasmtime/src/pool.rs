@@ -0,0 +1,114 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, TryLockError,
+};
+
+use wasm_offload::{ModuleHandle, SharedOffloadTarget, Val};
+
+use crate::{
+    cache::ModuleCache,
+    runtime::{Call, Instances, Runtime},
+    CallOptions, CallOutcome, WasmtimeOffloadBuilder, WasmtimeOffloadError,
+};
+
+/// A [`SharedOffloadTarget`] that runs up to a fixed number of calls in parallel.
+///
+/// All workers share one engine and one cache of compiled components; each worker has its own
+/// stores, so the [`InstancePolicy`](crate::InstancePolicy) applies per worker.
+pub struct WasmtimeOffloadPool {
+    pub(crate) runtime: Runtime,
+    pub(crate) modules: RwLock<ModuleCache>,
+    pub(crate) workers: Vec<Mutex<Instances>>,
+    pub(crate) next: AtomicUsize,
+}
+
+impl WasmtimeOffloadPool {
+    /// Creates a pool with `workers` concurrent workers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn new(workers: usize) -> Result<Self, WasmtimeOffloadError> {
+        WasmtimeOffloadBuilder::new().build_pool(workers)
+    }
+
+    /// Like [`SharedOffloadTarget::call`], with per-call `options` such as a deadline. The
+    /// outcome also holds the output of the guest if it is captured.
+    pub fn call_with_options(
+        &self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
+        options: &CallOptions,
+    ) -> Result<CallOutcome, WasmtimeOffloadError> {
+        let compiled = self.modules().get(module)?.clone();
+        let call = Call {
+            name,
+            args,
+            returns,
+            options,
+        };
+        self.worker().call(&self.runtime, &compiled, module, call)
+    }
+
+    fn modules(&self) -> RwLockReadGuard<'_, ModuleCache> {
+        // The cache is never left half-updated, so a poisoned lock is still usable.
+        self.modules.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes the first idle worker, or waits for one if all of them are busy.
+    fn worker(&self) -> MutexGuard<'_, Instances> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.workers.len();
+        for i in 0..count {
+            let worker = &self.workers[(start + i) % count];
+            match worker.try_lock() {
+                Ok(guard) => return guard,
+                Err(TryLockError::Poisoned(poisoned)) => return recover(worker, poisoned),
+                Err(TryLockError::WouldBlock) => {}
+            }
+        }
+        let worker = &self.workers[start % count];
+        worker
+            .lock()
+            .unwrap_or_else(|poisoned| recover(worker, poisoned))
+    }
+}
+
+/// A worker that panicked mid-call may hold an instance in an unknown state, so its
+/// instances are dropped before it is used again.
+fn recover<'a>(
+    worker: &Mutex<Instances>,
+    poisoned: PoisonError<MutexGuard<'a, Instances>>,
+) -> MutexGuard<'a, Instances> {
+    let mut guard = poisoned.into_inner();
+    guard.clear();
+    worker.clear_poison();
+    guard
+}
+
+impl SharedOffloadTarget for WasmtimeOffloadPool {
+    type Error = WasmtimeOffloadError;
+
+    fn load_module(&self, module: &[u8]) -> Result<ModuleHandle, Self::Error> {
+        let hash = ModuleCache::hash(module);
+        if let Some(handle) = self.modules().lookup(&hash) {
+            return Ok(handle);
+        }
+        let module = self.runtime.compile(module)?;
+        let mut modules = self.modules.write().unwrap_or_else(PoisonError::into_inner);
+        Ok(modules.insert(hash, module))
+    }
+
+    fn call(
+        &self,
+        module: ModuleHandle,
+        name: &str,
+        args: &[Val],
+        returns: bool,
+    ) -> Result<Option<Val>, Self::Error> {
+        self.call_with_options(module, name, args, returns, &CallOptions::default())
+            .map(|outcome| outcome.value)
+    }
+}
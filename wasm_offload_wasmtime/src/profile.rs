@@ -0,0 +1,169 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Instant, SystemTime},
+};
+
+use fxprof_processed_profile::{
+    CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, ProcessHandle, Profile,
+    ReferenceTimestamp, SamplingInterval, ThreadHandle, Timestamp,
+};
+use wasmtime::{StoreContextMut, WasmBacktrace};
+
+use crate::{deadline::EPOCH_TICK, OffloaderState};
+
+/// Where the profiles recorded with
+/// [`WasmtimeOffloadBuilder::guest_profiling`](crate::WasmtimeOffloadBuilder::guest_profiling)
+/// are written. Profiles use the format of the Firefox profiler and can be opened at
+/// <https://profiler.firefox.com>.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProfileOutput {
+    /// One profile per offloaded function, written to `{dir}/{function}.json`.
+    PerFunction(PathBuf),
+    /// A single profile for all calls, with a track per offloaded function.
+    Session(PathBuf),
+}
+
+/// The stack of a guest at one epoch tick, outermost frame first.
+pub(crate) struct Sample {
+    time: Instant,
+    frames: Vec<String>,
+}
+
+/// Records the current stack of the guest running in `store` if it is being profiled.
+pub(crate) fn sample(store: &mut StoreContextMut<'_, OffloaderState>) {
+    if store.data().samples.is_none() {
+        return;
+    }
+    let backtrace = WasmBacktrace::capture(&*store);
+    let frames = backtrace
+        .frames()
+        .iter()
+        .rev()
+        .map(|frame| match frame.func_name() {
+            Some(name) => format!("{:#}", rustc_demangle::demangle(name)),
+            None => format!(
+                "{}!<wasm function {}>",
+                frame.module().name().unwrap_or("<unknown>"),
+                frame.func_index()
+            ),
+        })
+        .collect();
+    if let Some(samples) = &mut store.data_mut().samples {
+        samples.push(Sample {
+            time: Instant::now(),
+            frames,
+        });
+    }
+}
+
+/// A profile that is being recorded, with a thread for each function that appears in it.
+struct Recording {
+    profile: Profile,
+    process: ProcessHandle,
+    threads: HashMap<String, ThreadHandle>,
+}
+
+/// Collects the samples taken during calls into profiles and writes them to disk.
+pub(crate) struct Profiler {
+    output: ProfileOutput,
+    start: Instant,
+    reference: SystemTime,
+    recordings: Mutex<HashMap<PathBuf, Recording>>,
+}
+
+impl Profiler {
+    pub(crate) fn new(output: ProfileOutput) -> Self {
+        Self {
+            output,
+            start: Instant::now(),
+            reference: SystemTime::now(),
+            recordings: Mutex::default(),
+        }
+    }
+
+    fn timestamp(&self, time: Instant) -> Timestamp {
+        Timestamp::from_nanos_since_reference(time.duration_since(self.start).as_nanos() as u64)
+    }
+
+    /// Adds the `samples` taken during a call of `function` to its profile and rewrites the
+    /// profile file, so that it is up to date even if the offloader is never dropped.
+    pub(crate) fn record(&self, function: &str, samples: Vec<Sample>) {
+        // Calls that finish within a tick have no samples and don't show up in a profile.
+        let Some(last) = samples.last().map(|sample| sample.time) else {
+            return;
+        };
+        let path = match &self.output {
+            ProfileOutput::PerFunction(dir) => dir.join(format!("{function}.json")),
+            ProfileOutput::Session(path) => path.clone(),
+        };
+        // A profile that fails to write is still complete in memory, so a poisoned lock is
+        // still usable.
+        let mut recordings = self
+            .recordings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let recording = recordings.entry(path.clone()).or_insert_with(|| {
+            let mut profile = Profile::new(
+                "wasm-offload",
+                ReferenceTimestamp::from(self.reference),
+                SamplingInterval::from_nanos(EPOCH_TICK.as_nanos() as u64),
+            );
+            let process = profile.add_process(
+                "wasm-offload",
+                std::process::id(),
+                Timestamp::from_millis_since_reference(0.0),
+            );
+            Recording {
+                profile,
+                process,
+                threads: HashMap::new(),
+            }
+        });
+        let tid = recording.threads.len() as u32;
+        let profile = &mut recording.profile;
+        let thread = match recording.threads.entry(function.to_string()) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let start = self.timestamp(samples[0].time);
+                let thread = profile.add_thread(recording.process, tid, start, false);
+                profile.set_thread_name(thread, function);
+                *entry.insert(thread)
+            }
+        };
+        for sample in samples {
+            let frames: Vec<_> = sample
+                .frames
+                .iter()
+                .map(|frame| FrameInfo {
+                    frame: Frame::Label(profile.intern_string(frame)),
+                    category_pair: CategoryHandle::OTHER.into(),
+                    flags: FrameFlags::empty(),
+                })
+                .collect();
+            profile.add_sample(
+                thread,
+                self.timestamp(sample.time),
+                frames.into_iter(),
+                CpuDelta::from(EPOCH_TICK),
+                1,
+            );
+        }
+        profile.set_thread_end_time(thread, self.timestamp(last));
+        if let Err(err) = write(&path, profile) {
+            log::warn!("failed to write guest profile to {}: {err}", path.display());
+        }
+    }
+}
+
+fn write(path: &Path, profile: &Profile) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut file, profile)?;
+    file.flush()
+}
@@ -0,0 +1,261 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use wasm_offload::{ModuleHandle, Val};
+use wasmtime::{
+    component::{types::ComponentItem, Component, ComponentExportIndex, Instance, Linker},
+    Engine, Store,
+};
+
+use crate::{
+    cache::Module,
+    deadline::{self, Deadline, Ticker},
+    limits::{self, Limits},
+    profile::{self, Profiler},
+    types,
+    wasi::WasiConfig,
+    CallOptions, CallOutcome, InstancePolicy, OffloaderState, ValExt, WasmtimeOffloadError,
+    WtValExt,
+};
+
+/// The parts of an offloader that are shared by all of its calls.
+pub(crate) struct Runtime {
+    pub(crate) engine: Engine,
+    pub(crate) linker: Linker<OffloaderState>,
+    pub(crate) policy: InstancePolicy,
+    pub(crate) limits: Limits,
+    pub(crate) wasi: WasiConfig,
+    pub(crate) profiler: Option<Profiler>,
+    /// Whether guests run on an async executor and yield to it while they run.
+    pub(crate) yields: bool,
+    /// Advances the epoch of the engine, if guests are interrupted on epoch ticks.
+    pub(crate) ticker: Option<Ticker>,
+}
+
+impl Runtime {
+    pub(crate) fn compile(&self, module: &[u8]) -> Result<Module, WasmtimeOffloadError> {
+        let component =
+            Component::new(&self.engine, module).map_err(WasmtimeOffloadError::Compilation)?;
+        self.link(&component, module)
+    }
+
+    /// Resolves the imports of `component`, which was compiled from `module`.
+    pub(crate) fn link(
+        &self,
+        component: &Component,
+        module: &[u8],
+    ) -> Result<Module, WasmtimeOffloadError> {
+        let core_instances =
+            limits::core_instances(module).map_err(WasmtimeOffloadError::Compilation)?;
+        let pre = self
+            .linker
+            .instantiate_pre(component)
+            .map_err(WasmtimeOffloadError::instantiation)?;
+        Ok(Module {
+            pre,
+            core_instances,
+        })
+    }
+
+    /// Looks up the exported function `name`, checks `args` against its parameters and makes
+    /// sure that its result can be passed as a `Val`.
+    pub(crate) fn export(
+        &self,
+        module: &Module,
+        name: &str,
+        args: &[Val],
+    ) -> Result<ComponentExportIndex, WasmtimeOffloadError> {
+        let component = module.pre.component();
+        let Some((ComponentItem::ComponentFunc(ty), index)) = component.export_index(None, name)
+        else {
+            return Err(WasmtimeOffloadError::ExportNotFound {
+                name: name.to_string(),
+                available: component
+                    .component_type()
+                    .exports(&self.engine)
+                    .filter(|(_, item)| matches!(item, ComponentItem::ComponentFunc(_)))
+                    .map(|(name, _)| name.to_string())
+                    .collect(),
+            });
+        };
+        if ty.params().len() != args.len() {
+            return Err(WasmtimeOffloadError::ArgumentCount {
+                function: name.to_string(),
+                expected: ty.params().len(),
+                found: args.len(),
+            });
+        }
+        for (param, (ty, arg)) in ty.params().zip(args).enumerate() {
+            // Resource handles can't be passed as a `Val`, so there is nothing to check them
+            // against.
+            if let Some(ty) = types::to_val_type(&ty) {
+                arg.type_check(&ty)
+                    .map_err(|mismatch| WasmtimeOffloadError::TypeMismatch {
+                        function: name.to_string(),
+                        param,
+                        mismatch,
+                    })?;
+            }
+        }
+        if ty.results().any(|ty| types::to_val_type(&ty).is_none()) {
+            return Err(WasmtimeOffloadError::UnsupportedResult {
+                function: name.to_string(),
+            });
+        }
+        Ok(index)
+    }
+
+    pub(crate) fn instantiate(
+        &self,
+        module: &Module,
+    ) -> Result<(Store<OffloaderState>, Instance), WasmtimeOffloadError> {
+        self.check_instances(module)?;
+        let mut store = self.new_store()?;
+        let instance = module
+            .pre
+            .instantiate(&mut store)
+            .map_err(WasmtimeOffloadError::instantiation)?;
+        Ok((store, instance))
+    }
+
+    /// Fails if an instance of `module` would consist of more core instances than the limit
+    /// allows.
+    pub(crate) fn check_instances(&self, module: &Module) -> Result<(), WasmtimeOffloadError> {
+        match self.limits.instances {
+            Some(limit) if module.core_instances > limit => {
+                Err(WasmtimeOffloadError::instance_limit())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn new_store(&self) -> Result<Store<OffloaderState>, WasmtimeOffloadError> {
+        let (ctx, output) = self.wasi.build()?;
+        self.store(OffloaderState::new(ctx, output, self.limits))
+    }
+
+    fn store(&self, state: OffloaderState) -> Result<Store<OffloaderState>, WasmtimeOffloadError> {
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limiter);
+        let yields = self.yields;
+        store.epoch_deadline_callback(move |mut store| {
+            profile::sample(&mut store);
+            let state = store.data();
+            deadline::on_epoch(state.deadline, state.samples.is_some(), yields)
+        });
+        // Instantiation may run guest code too, so it gets a budget of its own.
+        self.arm(&mut store, &CallOptions::default())?;
+        Ok(store)
+    }
+
+    /// Resets the fuel and deadline of `store` for a call made with `options`.
+    pub(crate) fn arm(
+        &self,
+        store: &mut Store<OffloaderState>,
+        options: &CallOptions,
+    ) -> Result<(), WasmtimeOffloadError> {
+        if options.deadline.is_some() && self.ticker.is_none() {
+            return Err(WasmtimeOffloadError::DeadlinesDisabled);
+        }
+        if let Some(fuel) = self.limits.fuel {
+            store.set_fuel(fuel)?;
+        }
+        let deadline = options.deadline.map(Deadline::after);
+        let state = store.data_mut();
+        state.deadline = deadline;
+        state.panic = None;
+        state.samples = self.profiler.is_some().then(Vec::new);
+        store.set_epoch_deadline(deadline::ticks(
+            deadline,
+            self.profiler.is_some() || self.yields,
+        ));
+        Ok(())
+    }
+
+    /// Adds the samples taken during the call of `function` that just finished in `store` to
+    /// its profile.
+    pub(crate) fn finish_profile(&self, store: &mut Store<OffloaderState>, function: &str) {
+        if let (Some(profiler), Some(samples)) = (&self.profiler, store.data_mut().samples.take()) {
+            profiler.record(function, samples);
+        }
+    }
+}
+
+/// The arguments of a single call.
+#[derive(Clone, Copy)]
+pub(crate) struct Call<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) args: &'a [Val],
+    pub(crate) returns: bool,
+    pub(crate) options: &'a CallOptions,
+}
+
+/// Stores for synchronous calls, holding the instances kept alive under
+/// [`InstancePolicy::Reuse`].
+#[derive(Default)]
+pub(crate) struct Instances(HashMap<ModuleHandle, (Store<OffloaderState>, Instance)>);
+
+impl Instances {
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub(crate) fn call(
+        &mut self,
+        runtime: &Runtime,
+        module: &Module,
+        handle: ModuleHandle,
+        call: Call<'_>,
+    ) -> Result<CallOutcome, WasmtimeOffloadError> {
+        let export = runtime.export(module, call.name, call.args)?;
+        let mut fresh;
+        let (store, instance) = match runtime.policy {
+            InstancePolicy::Reuse => match self.0.entry(handle) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(runtime.instantiate(module)?),
+            },
+            InstancePolicy::FreshPerCall | InstancePolicy::Pooled { .. } => {
+                fresh = runtime.instantiate(module)?;
+                &mut fresh
+            }
+        };
+        let result = invoke(runtime, store, instance, &export, call);
+        if result.is_err() && runtime.policy == InstancePolicy::Reuse {
+            // A call that trapped or was interrupted may have left the guest in an
+            // inconsistent state, so the next call starts over with a new instance.
+            self.0.remove(&handle);
+        }
+        result
+    }
+}
+
+fn invoke(
+    runtime: &Runtime,
+    store: &mut Store<OffloaderState>,
+    instance: &Instance,
+    export: &ComponentExportIndex,
+    call: Call<'_>,
+) -> Result<CallOutcome, WasmtimeOffloadError> {
+    let func = instance
+        .get_func(&mut *store, export)
+        .expect("export was looked up in the instantiated component");
+
+    let mut output = if call.returns {
+        vec![wasmtime::component::Val::U32(0)]
+    } else {
+        vec![]
+    };
+    let args: Vec<_> = call.args.iter().map(ValExt::to_wasmtime).collect();
+    runtime.arm(store, call.options)?;
+    store.data().begin_output(call.name);
+    let result = func
+        .call(&mut *store, &args, &mut output)
+        .and_then(|()| func.post_return(&mut *store));
+    let (stdout, stderr) = store.data().finish_output();
+    runtime.finish_profile(store, call.name);
+    result.map_err(|err| store.data_mut().call_error(err, call.name))?;
+    Ok(CallOutcome {
+        value: output.first().map(WtValExt::to_offload),
+        stdout,
+        stderr,
+    })
+}
@@ -0,0 +1,61 @@
+use wasm_offload::ValType;
+use wasmtime::component::Type;
+
+/// Converts a wasmtime component type into the equivalent [`ValType`], or `None` for resource
+/// handles, which can't be passed as a [`Val`](wasm_offload::Val).
+pub(crate) fn to_val_type(ty: &Type) -> Option<ValType> {
+    Some(match ty {
+        Type::Bool => ValType::Bool,
+        Type::S8 => ValType::S8,
+        Type::U8 => ValType::U8,
+        Type::S16 => ValType::S16,
+        Type::U16 => ValType::U16,
+        Type::S32 => ValType::S32,
+        Type::U32 => ValType::U32,
+        Type::S64 => ValType::S64,
+        Type::U64 => ValType::U64,
+        Type::Float32 => ValType::Float32,
+        Type::Float64 => ValType::Float64,
+        Type::Char => ValType::Char,
+        Type::String => ValType::String,
+        Type::List(list) => ValType::List(Box::new(to_val_type(&list.ty())?)),
+        Type::Record(record) => ValType::Record(
+            record
+                .fields()
+                .map(|field| Some((field.name.to_string(), to_val_type(&field.ty)?)))
+                .collect::<Option<_>>()?,
+        ),
+        Type::Tuple(tuple) => ValType::Tuple(
+            tuple
+                .types()
+                .map(|ty| to_val_type(&ty))
+                .collect::<Option<_>>()?,
+        ),
+        Type::Variant(variant) => ValType::Variant(
+            variant
+                .cases()
+                .map(|case| {
+                    let ty = match &case.ty {
+                        Some(ty) => Some(to_val_type(ty)?),
+                        None => None,
+                    };
+                    Some((case.name.to_string(), ty))
+                })
+                .collect::<Option<_>>()?,
+        ),
+        Type::Enum(enum_) => ValType::Enum(enum_.names().map(str::to_string).collect()),
+        Type::Option(option) => ValType::Option(Box::new(to_val_type(&option.ty())?)),
+        Type::Result(result) => ValType::Result {
+            ok: match result.ok() {
+                Some(ty) => Some(Box::new(to_val_type(&ty)?)),
+                None => None,
+            },
+            err: match result.err() {
+                Some(ty) => Some(Box::new(to_val_type(&ty)?)),
+                None => None,
+            },
+        },
+        Type::Flags(flags) => ValType::Flags(flags.names().map(str::to_string).collect()),
+        Type::Own(_) | Type::Borrow(_) => return None,
+    })
+}
@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+
+use wasmtime_wasi::{DirPerms, FilePerms, WasiCtx, WasiCtxBuilder};
+
+use crate::output::OutputPipes;
+
+/// Where the standard streams of a guest are connected to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Stdio {
+    /// Stdin is empty and output is discarded.
+    #[default]
+    Null,
+    /// The guest reads from and writes to the streams of the host process.
+    Inherit,
+    /// Output is collected per call and returned in a [`CallOutcome`](crate::CallOutcome).
+    Capture,
+    /// Every line of output is logged through the [`log`] crate, prefixed with the name of the
+    /// function that wrote it. Stdout is logged at info level, stderr at warn level.
+    Log,
+}
+
+/// What a guest may do in a preopened directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirAccess {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Clone, Debug)]
+struct PreopenedDir {
+    host: PathBuf,
+    guest: String,
+    access: DirAccess,
+}
+
+/// The WASI environment offloaded functions run in.
+///
+/// By default a guest sees no environment variables, arguments or directories, and has no
+/// stdio.
+#[derive(Clone, Debug, Default)]
+pub struct WasiConfig {
+    env: Vec<(String, String)>,
+    args: Vec<String>,
+    stdio: Stdio,
+    dirs: Vec<PreopenedDir>,
+}
+
+impl WasiConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn stdio(mut self, stdio: Stdio) -> Self {
+        self.stdio = stdio;
+        self
+    }
+
+    /// Makes the host directory `host` available to the guest as `guest`.
+    pub fn preopened_dir(
+        mut self,
+        host: impl Into<PathBuf>,
+        guest: impl Into<String>,
+        access: DirAccess,
+    ) -> Self {
+        self.dirs.push(PreopenedDir {
+            host: host.into(),
+            guest: guest.into(),
+            access,
+        });
+        self
+    }
+
+    /// Creates the context for a new store, along with the pipes its output goes to unless it
+    /// is discarded or inherited. Fails if a preopened directory can't be opened.
+    pub(crate) fn build(&self) -> wasmtime::Result<(WasiCtx, Option<OutputPipes>)> {
+        let mut builder = WasiCtxBuilder::new();
+        for (key, value) in &self.env {
+            builder.env(key, value);
+        }
+        builder.args(&self.args);
+        let output = match self.stdio {
+            Stdio::Null => None,
+            Stdio::Inherit => {
+                builder.inherit_stdio();
+                None
+            }
+            Stdio::Capture => Some(OutputPipes::capture()),
+            Stdio::Log => Some(OutputPipes::log()),
+        };
+        if let Some(output) = &output {
+            builder
+                .stdout(output.stdout.clone())
+                .stderr(output.stderr.clone());
+        }
+        for dir in &self.dirs {
+            let (dir_perms, file_perms) = match dir.access {
+                DirAccess::ReadOnly => (DirPerms::READ, FilePerms::READ),
+                DirAccess::ReadWrite => (DirPerms::all(), FilePerms::all()),
+            };
+            builder.preopened_dir(&dir.host, &dir.guest, dir_perms, file_perms)?;
+        }
+        Ok((builder.build(), output))
+    }
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use wasm_offload::AsyncOffloadTarget;
+use wasm_offload_wasmtime::{
+    AsyncWasmtimeOffload, CallOptions, InstancePolicy, WasmtimeOffloadError,
+};
+
+use common::SPIN;
+
+/// Both futures run on the same thread. The counting one can only finish before the call if
+/// the spinning guest yields to the executor.
+#[tokio::test(flavor = "current_thread")]
+async fn spinning_guest_lets_other_tasks_run() {
+    let offloader = AsyncWasmtimeOffload::new().unwrap();
+    let module = offloader.load_module(SPIN.as_bytes()).await.unwrap();
+    let options = CallOptions::new().deadline(Duration::from_millis(500));
+
+    let call = async {
+        let result = offloader
+            .call_with_options(module, "spin", &[], false, &options)
+            .await;
+        (result, Instant::now())
+    };
+    let count = async {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        Instant::now()
+    };
+    let ((result, call_done), count_done) = tokio::join!(call, count);
+
+    assert!(matches!(result, Err(WasmtimeOffloadError::Timeout { .. })));
+    assert!(count_done < call_done);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn calls_beyond_the_pool_size_fail() {
+    let offloader = AsyncWasmtimeOffload::builder()
+        .instance_policy(InstancePolicy::Pooled { max: 1 })
+        .build_async()
+        .unwrap();
+    let module = offloader.load_module(SPIN.as_bytes()).await.unwrap();
+    let options = CallOptions::new().deadline(Duration::from_millis(100));
+    let spin = || offloader.call_with_options(module, "spin", &[], false, &options);
+
+    let (first, second) = tokio::join!(spin(), spin());
+    assert!(matches!(first, Err(WasmtimeOffloadError::Timeout { .. })));
+    assert!(matches!(
+        second,
+        Err(WasmtimeOffloadError::PoolExhausted(_))
+    ));
+    // The slot is free again once the first call is done.
+    assert!(matches!(
+        spin().await,
+        Err(WasmtimeOffloadError::Timeout { .. })
+    ));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn instantiating_beyond_the_instance_limit_fails() {
+    let offloader = AsyncWasmtimeOffload::builder()
+        .max_instances(1)
+        .build_async()
+        .unwrap();
+    let two_instances = SPIN.replace(
+        "(core instance $i (instantiate $m))",
+        "(core instance $i (instantiate $m)) (core instance (instantiate $m))",
+    );
+    let module = offloader
+        .load_module(two_instances.as_bytes())
+        .await
+        .unwrap();
+
+    let result = offloader.call(module, "spin", &[], false).await;
+    assert!(matches!(
+        result,
+        Err(WasmtimeOffloadError::LimitExceeded {
+            resource: "instance"
+        })
+    ));
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use std::time::Duration;
+
+use wasm_offload::OffloadTarget;
+use wasm_offload_wasmtime::{CallOptions, WasmtimeOffload, WasmtimeOffloadError};
+
+use common::SPIN;
+
+#[test]
+fn deadlines_must_be_enabled() {
+    let mut offloader = WasmtimeOffload::builder().build().unwrap();
+    let module = offloader.load_module(SPIN.as_bytes()).unwrap();
+    let options = CallOptions::new().deadline(Duration::from_millis(50));
+
+    let result = offloader.call_with_options(module, "spin", &[], false, &options);
+    assert!(matches!(
+        result,
+        Err(WasmtimeOffloadError::DeadlinesDisabled)
+    ));
+}
+
+#[test]
+fn calls_are_interrupted_at_their_deadline() {
+    let mut offloader = WasmtimeOffload::builder().deadlines(true).build().unwrap();
+    let module = offloader.load_module(SPIN.as_bytes()).unwrap();
+    let options = CallOptions::new().deadline(Duration::from_millis(50));
+
+    let result = offloader.call_with_options(module, "spin", &[], false, &options);
+    assert!(matches!(result, Err(WasmtimeOffloadError::Timeout { .. })));
+}
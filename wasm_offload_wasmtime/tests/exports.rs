@@ -0,0 +1,82 @@
+use wasm_offload::{OffloadTarget, Val};
+use wasm_offload_wasmtime::{WasmtimeOffload, WasmtimeOffloadError};
+
+/// A component with an `add` export and a `make` export that returns a resource.
+const GUEST: &str = r#"
+(component
+  (type $r (resource (rep i32)))
+  (export $handle "handle" (type $r))
+  (core func $new (canon resource.new $r))
+  (core module $m
+    (import "" "new" (func $new (param i32) (result i32)))
+    (func (export "add") (param i32 i32) (result i32)
+      local.get 0
+      local.get 1
+      i32.add)
+    (func (export "make") (result i32)
+      i32.const 7
+      call $new))
+  (core instance $i (instantiate $m
+    (with "" (instance (export "new" (func $new))))))
+  (func (export "add") (param "a" s32) (param "b" s32) (result s32)
+    (canon lift (core func $i "add")))
+  (func (export "make") (result (own $handle))
+    (canon lift (core func $i "make"))))
+"#;
+
+fn load() -> (WasmtimeOffload, wasm_offload::ModuleHandle) {
+    let mut offloader = WasmtimeOffload::new().unwrap();
+    let module = offloader.load_module(GUEST.as_bytes()).unwrap();
+    (offloader, module)
+}
+
+#[test]
+fn missing_exports_list_the_available_functions() {
+    let (mut offloader, module) = load();
+    let result = offloader.call(module, "sub", &[], true);
+    let Err(WasmtimeOffloadError::ExportNotFound { name, available }) = result else {
+        panic!("unexpected result: {result:?}");
+    };
+    assert_eq!(name, "sub");
+    assert_eq!(available, ["add", "make"]);
+}
+
+#[test]
+fn mismatched_arguments_name_their_position() {
+    let (mut offloader, module) = load();
+    let result = offloader.call(module, "add", &[Val::S32(1)], true);
+    assert!(matches!(
+        result,
+        Err(WasmtimeOffloadError::ArgumentCount {
+            expected: 2,
+            found: 1,
+            ..
+        })
+    ));
+
+    let args = [Val::S32(1), Val::String("2".to_string())];
+    let result = offloader.call(module, "add", &args, true);
+    let Err(WasmtimeOffloadError::TypeMismatch {
+        function,
+        param,
+        mismatch,
+    }) = result
+    else {
+        panic!("unexpected result: {result:?}");
+    };
+    assert_eq!((function.as_str(), param), ("add", 1));
+    assert_eq!((mismatch.expected(), mismatch.found()), ("s32", "string"));
+
+    let result = offloader.call(module, "add", &[Val::S32(1), Val::S32(2)], true);
+    assert!(matches!(result, Ok(Some(Val::S32(3)))));
+}
+
+#[test]
+fn resource_results_are_rejected() {
+    let (mut offloader, module) = load();
+    let result = offloader.call(module, "make", &[], true);
+    assert!(matches!(
+        result,
+        Err(WasmtimeOffloadError::UnsupportedResult { function }) if function == "make"
+    ));
+}
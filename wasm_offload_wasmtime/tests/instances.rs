@@ -0,0 +1,63 @@
+use wasm_offload::{OffloadTarget, Val};
+use wasm_offload_wasmtime::{InstancePolicy, WasmtimeOffload, WasmtimeOffloadError};
+
+/// A component whose `count` export increments a global and returns its new value, and whose
+/// `trap` export traps.
+const COUNTER: &str = r#"
+(component
+  (core module $m
+    (global $count (mut i32) (i32.const 0))
+    (func (export "count") (result i32)
+      global.get $count
+      i32.const 1
+      i32.add
+      global.set $count
+      global.get $count)
+    (func (export "trap") unreachable))
+  (core instance $i (instantiate $m))
+  (func (export "count") (result s32) (canon lift (core func $i "count")))
+  (func (export "trap") (canon lift (core func $i "trap"))))
+"#;
+
+fn count(offloader: &mut WasmtimeOffload, module: wasm_offload::ModuleHandle) -> i32 {
+    match offloader.call(module, "count", &[], true).unwrap() {
+        Some(Val::S32(count)) => count,
+        other => panic!("unexpected result: {other:?}"),
+    }
+}
+
+/// The results of three calls to `count` under `policy`.
+fn counts(policy: InstancePolicy) -> Vec<i32> {
+    let mut offloader = WasmtimeOffload::builder()
+        .instance_policy(policy)
+        .build()
+        .unwrap();
+    let module = offloader.load_module(COUNTER.as_bytes()).unwrap();
+    (0..3).map(|_| count(&mut offloader, module)).collect()
+}
+
+#[test]
+fn reused_instances_keep_their_globals() {
+    assert_eq!(counts(InstancePolicy::Reuse), [1, 2, 3]);
+}
+
+#[test]
+fn fresh_instances_start_over() {
+    assert_eq!(counts(InstancePolicy::FreshPerCall), [1, 1, 1]);
+    assert_eq!(counts(InstancePolicy::Pooled { max: 1 }), [1, 1, 1]);
+}
+
+#[test]
+fn reused_instances_are_dropped_after_an_error() {
+    let mut offloader = WasmtimeOffload::builder()
+        .instance_policy(InstancePolicy::Reuse)
+        .build()
+        .unwrap();
+    let module = offloader.load_module(COUNTER.as_bytes()).unwrap();
+    assert_eq!(count(&mut offloader, module), 1);
+    assert_eq!(count(&mut offloader, module), 2);
+
+    let result = offloader.call(module, "trap", &[], false);
+    assert!(matches!(result, Err(WasmtimeOffloadError::Trap { .. })));
+    assert_eq!(count(&mut offloader, module), 1);
+}
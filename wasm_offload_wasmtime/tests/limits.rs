@@ -0,0 +1,93 @@
+use wasm_offload::{OffloadTarget, Val};
+use wasm_offload_wasmtime::{WasmtimeOffload, WasmtimeOffloadError};
+
+/// A component whose `spin` export loops forever and whose `grow` export grows its memory of
+/// one page by the given number of pages.
+const GUEST: &str = r#"
+(component
+  (core module $m
+    (memory 1)
+    (func (export "spin") (loop $l br $l))
+    (func (export "grow") (param i32) (result i32)
+      local.get 0
+      memory.grow))
+  (core instance $i (instantiate $m))
+  (func (export "spin") (canon lift (core func $i "spin")))
+  (func (export "grow") (param "pages" u32) (result s32)
+    (canon lift (core func $i "grow"))))
+"#;
+
+/// A component that instantiates a nested component of two core instances twice, for five
+/// core instances in total.
+const NESTED: &str = r#"
+(component
+  (component $c
+    (core module $m
+      (func (export "noop")))
+    (core instance $a (instantiate $m))
+    (core instance (instantiate $m))
+    (func (export "noop") (canon lift (core func $a "noop"))))
+  (instance $x (instantiate $c))
+  (instance (instantiate $c))
+  (core module $m)
+  (core instance (instantiate $m))
+  (alias export $x "noop" (func $noop))
+  (export "noop" (func $noop)))
+"#;
+
+const PAGE: usize = 64 * 1024;
+
+#[test]
+fn calls_run_out_of_fuel() {
+    let mut offloader = WasmtimeOffload::builder().fuel(10_000).build().unwrap();
+    let module = offloader.load_module(GUEST.as_bytes()).unwrap();
+
+    let result = offloader.call(module, "spin", &[], false);
+    assert!(matches!(result, Err(WasmtimeOffloadError::OutOfFuel)));
+}
+
+#[test]
+fn memories_grow_up_to_the_limit() {
+    let mut offloader = WasmtimeOffload::builder()
+        .max_memory_size(2 * PAGE)
+        .build()
+        .unwrap();
+    let module = offloader.load_module(GUEST.as_bytes()).unwrap();
+
+    let result = offloader
+        .call(module, "grow", &[Val::U32(1)], true)
+        .unwrap();
+    assert!(matches!(result, Some(Val::S32(1))));
+
+    let result = offloader.call(module, "grow", &[Val::U32(2)], true);
+    assert!(matches!(
+        result,
+        Err(WasmtimeOffloadError::MemoryLimitExceeded {
+            requested,
+            limit,
+        }) if requested == 3 * PAGE && limit == 2 * PAGE
+    ));
+}
+
+#[test]
+fn nested_instances_count_toward_the_limit() {
+    for (limit, fits) in [(5, true), (4, false)] {
+        let mut offloader = WasmtimeOffload::builder()
+            .max_instances(limit)
+            .build()
+            .unwrap();
+        let module = offloader.load_module(NESTED.as_bytes()).unwrap();
+
+        let result = offloader.call(module, "noop", &[], false);
+        if fits {
+            result.unwrap();
+        } else {
+            assert!(matches!(
+                result,
+                Err(WasmtimeOffloadError::LimitExceeded {
+                    resource: "instance"
+                })
+            ));
+        }
+    }
+}